@@ -0,0 +1,326 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{ self, Read },
+    path::Path,
+    sync::Arc,
+    sync::atomic::{ AtomicBool, Ordering },
+    sync::mpsc::{ self, RecvTimeoutError },
+    thread::{ self, JoinHandle },
+    time::{ Duration, Instant },
+};
+use bus::Bus;
+
+const RESAMPLER_ZERO_CROSSINGS: f64 = 16.0; // sinc lobes kept either side of each output sample
+const SOURCE_CHUNK_FRAMES: usize = 512; // frames a file or pipe source reads at a time, like a device buffer
+const STDIN_QUEUE_CHUNKS: usize = 16; // chunks read ahead of the source thread
+const STDIN_POLL_INTERVAL: Duration = Duration::from_millis(50); // how often a source starved by stdin checks for shutdown
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum PcmFormat {
+    // 32-bit float, little endian
+    F32,
+    // signed 16-bit, little endian
+    I16,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ChannelMode {
+    // average all channels into one stream
+    Downmix,
+    // track a single channel
+    Select,
+    // track every channel separately
+    Split,
+}
+
+// how interleaved input is turned into the mono streams the pitch threads analyse
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelRouting {
+    pub mode: ChannelMode,
+    pub channel: usize, // used by select, counting from 0
+}
+
+impl ChannelRouting {
+    // number of mono streams produced from input with this many channels
+    pub fn num_outputs(&self, channels: usize) -> Result<usize, Box<dyn Error>> {
+        match self.mode {
+            ChannelMode::Select if self.channel >= channels =>
+                Err(format!("input channel {} was selected but the input only has {} channels", self.channel, channels).into()),
+            ChannelMode::Split => Ok(channels.max(1)),
+            _ => Ok(1),
+        }
+    }
+
+    pub fn route(&self, interleaved: &[f32], channels: usize) -> Vec<Vec<f32>> {
+        let channels = channels.max(1);
+        match self.mode {
+            ChannelMode::Downmix => vec![
+                interleaved
+                    .chunks(channels)
+                    .map(|frame| frame.iter().sum::<f32>() / (channels as f32))
+                    .collect()
+            ],
+            ChannelMode::Select => vec![
+                interleaved
+                    .chunks(channels)
+                    .map(|frame| frame.get(self.channel).copied().unwrap_or(0.0))
+                    .collect()
+            ],
+            ChannelMode::Split => (0..channels)
+                .map(|channel| interleaved.iter().skip(channel).step_by(channels).copied().collect())
+                .collect(),
+        }
+    }
+}
+
+// reads any integer or float wav normalised to [-1, 1], returning (interleaved samples, channels, sample rate)
+pub fn read_wav(path: &Path) -> Result<(Vec<f32>, usize, u32), Box<dyn Error>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| (s as f32) / scale))
+                .collect::<Result<Vec<f32>, _>>()?
+        }
+    };
+    Ok((interleaved, spec.channels.max(1) as usize, spec.sample_rate))
+}
+
+// interleaved raw pcm from stdin, ending at eof or once running is cleared. a blocking read from stdin
+// can't be interrupted, so it happens on a detached thread that is simply left behind at shutdown
+pub fn stdin_samples(format: PcmFormat, running: Arc<AtomicBool>) -> io::Result<impl Iterator<Item = f32> + Send> {
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<Vec<f32>>(STDIN_QUEUE_CHUNKS);
+    thread::Builder
+        ::new()
+        .name("StdinReaderThread".to_string())
+        .spawn(move || {
+            let mut stdin = io::stdin();
+            let width = match format {
+                PcmFormat::F32 => 4,
+                PcmFormat::I16 => 2,
+            };
+            let mut bytes = vec![0u8; SOURCE_CHUNK_FRAMES * width];
+            let mut filled = 0;
+            loop {
+                // whatever has arrived is passed on, a sample split between reads waits for its other half
+                filled += match stdin.read(&mut bytes[filled..]) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                let whole = filled - (filled % width);
+                let chunk = bytes[..whole]
+                    .chunks_exact(width)
+                    .map(|sample| match format {
+                        PcmFormat::F32 => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+                        PcmFormat::I16 => (i16::from_le_bytes([sample[0], sample[1]]) as f32) / 32768.0,
+                    })
+                    .collect::<Vec<f32>>();
+                bytes.copy_within(whole..filled, 0);
+                filled -= whole;
+                if chunk_tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    let mut buffered: VecDeque<f32> = VecDeque::new();
+    Ok(
+        std::iter::from_fn(move || {
+            while buffered.is_empty() {
+                if !running.load(Ordering::SeqCst) {
+                    return None;
+                }
+                match chunk_rx.recv_timeout(STDIN_POLL_INTERVAL) {
+                    Ok(chunk) => buffered.extend(chunk),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return None;
+                    }
+                }
+            }
+            buffered.pop_front()
+        })
+    )
+}
+
+// microseconds from the start of the stream to the given sample
+pub fn sample_time(sample: u64, sr: u32) -> f64 {
+    ((sample as f64) * 1e6) / (sr as f64)
+}
+
+// streaming band-limited resampler, interpolating with a hann windowed sinc
+pub struct Resampler {
+    step: f64, // input samples per output sample
+    cutoff: f64, // passband edge as a fraction of the input nyquist, lowered when downsampling
+    half_width: f64, // kernel reach either side of an output sample, in input samples
+    history: Vec<f32>,
+    first: u64, // stream position of history[0]
+    next: f64, // stream position of the next output sample
+}
+
+impl Resampler {
+    pub fn new(in_sr: u32, out_sr: u32) -> Resampler {
+        let step = (in_sr as f64) / (out_sr as f64);
+        let cutoff = (1.0 / step).min(1.0);
+        Resampler {
+            step,
+            cutoff,
+            half_width: RESAMPLER_ZERO_CROSSINGS / cutoff,
+            history: Vec::new(),
+            first: 0,
+            next: 0.0,
+        }
+    }
+
+    // every output sample the new input completes, as (stream position in input samples, sample)
+    pub fn process(&mut self, input: &[f32]) -> Vec<(f64, f32)> {
+        self.history.extend_from_slice(input);
+        let end = (self.first + (self.history.len() as u64)) as f64;
+        let mut out = Vec::new();
+        while self.next + self.half_width < end {
+            out.push((self.next, self.interpolate(self.next)));
+            self.next += self.step;
+        }
+        let keep_from = ((self.next - self.half_width).floor().max(0.0) as u64).max(self.first);
+        self.history.drain(..(keep_from - self.first) as usize);
+        self.first = keep_from;
+        out
+    }
+
+    // samples before the start of the stream count as silence
+    fn interpolate(&self, position: f64) -> f32 {
+        let lo = (position - self.half_width).ceil().max(self.first as f64) as u64;
+        let hi = (position + self.half_width).floor() as u64;
+        (lo..=hi)
+            .map(|i| {
+                let d = position - (i as f64);
+                let x = std::f64::consts::PI * self.cutoff * d;
+                let sinc = if x.abs() < 1e-9 { 1.0 } else { x.sin() / x };
+                let window = 0.5 + 0.5 * ((std::f64::consts::PI * d) / self.half_width).cos();
+                self.cutoff * sinc * window * (self.history[(i - self.first) as usize] as f64)
+            })
+            .sum::<f64>() as f32
+    }
+}
+
+// regroups buffers of whatever size the source delivers into timestamped hops of hop_size samples,
+// resampling them first when the analysis runs at a different rate than the source
+pub struct Framer {
+    hop_size: usize,
+    in_sr: u32,
+    out_sr: u32,
+    resampler: Option<Resampler>,
+    received: u64, // input samples pushed so far
+    pending: Vec<(f64, f32)>,
+}
+
+impl Framer {
+    pub fn new(hop_size: usize, in_sr: u32, out_sr: u32) -> Framer {
+        Framer {
+            hop_size: hop_size.max(1),
+            in_sr,
+            out_sr,
+            resampler: (in_sr != out_sr).then(|| Resampler::new(in_sr, out_sr)),
+            received: 0,
+            pending: Vec::with_capacity(2 * hop_size),
+        }
+    }
+
+    // stamps samples from start_time on, returning every hop they complete
+    pub fn push(&mut self, samples: &[f32], start_time: f64) -> Vec<Vec<(f64, f32)>> {
+        let chunk_start = self.received as f64;
+        self.received += samples.len() as u64;
+        match &mut self.resampler {
+            Some(resampler) => {
+                // output samples wait for the kernel's lookahead, so they can fall before this chunk
+                let in_sr = self.in_sr as f64;
+                self.pending.extend(
+                    resampler
+                        .process(samples)
+                        .into_iter()
+                        .map(|(position, sample)| (start_time + ((position - chunk_start) * 1e6) / in_sr, sample))
+                );
+            }
+            None => {
+                self.pending.extend(
+                    samples
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &sample)| (start_time + sample_time(i as u64, self.in_sr), sample))
+                );
+            }
+        }
+        let complete = (self.pending.len() / self.hop_size) * self.hop_size;
+        self.pending
+            .drain(..complete)
+            .collect::<Vec<(f64, f32)>>()
+            .chunks(self.hop_size)
+            .map(|hop| hop.to_vec())
+            .collect()
+    }
+
+    // the incomplete hop left at the end of a stream, zero padded
+    pub fn flush(&mut self) -> Option<Vec<(f64, f32)>> {
+        let &(last_time, _) = self.pending.last()?;
+        let missing = self.hop_size - self.pending.len();
+        let mut hop = std::mem::take(&mut self.pending);
+        hop.extend((1..=missing).map(|i| (last_time + sample_time(i as u64, self.out_sr), 0.0)));
+        Some(hop)
+    }
+}
+
+// feeds the live pipeline from something other than a device, no faster than real time
+// so the ui and midi output behave as they would with a microphone. dropping the buses at
+// the end of the stream shuts the pitch threads down behind them
+pub fn spawn_source(
+    samples: impl Iterator<Item = f32> + Send + 'static,
+    channels: usize,
+    sr: u32,
+    routing: ChannelRouting,
+    mut framers: Vec<Framer>,
+    mut snapshot_buses: Vec<Bus<Vec<(f64, f32)>>>,
+    running: Arc<AtomicBool>
+) -> io::Result<JoinHandle<()>> {
+    thread::Builder
+        ::new()
+        .name("AudioSourceThread".to_string())
+        .spawn(move || {
+            let mut samples = samples;
+            let start = Instant::now();
+            let mut position: u64 = 0;
+            let chunk_len = SOURCE_CHUNK_FRAMES * channels;
+            let mut chunk: Vec<f32> = Vec::with_capacity(chunk_len);
+            while running.load(Ordering::SeqCst) {
+                chunk.clear();
+                chunk.extend(samples.by_ref().take(chunk_len));
+                if chunk.is_empty() {
+                    for (snapshot_bus, framer) in snapshot_buses.iter_mut().zip(framers.iter_mut()) {
+                        if let Some(hop) = framer.flush() {
+                            snapshot_bus.broadcast(hop);
+                        }
+                    }
+                    break;
+                }
+                let frames = chunk.len().div_ceil(channels) as u64;
+                // hold the chunk back until a device would have finished capturing it
+                let due = start + Duration::from_secs_f64(((position + frames) as f64) / (sr as f64));
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+                let streams = routing.route(&chunk, channels);
+                for ((snapshot_bus, framer), stream) in snapshot_buses.iter_mut().zip(framers.iter_mut()).zip(streams) {
+                    for hop in framer.push(&stream, sample_time(position, sr)) {
+                        snapshot_bus.broadcast(hop);
+                    }
+                }
+                position += frames;
+            }
+        })
+}
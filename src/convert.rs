@@ -0,0 +1,142 @@
+use std::{ error::Error, io, path::Path, sync::Arc, sync::atomic::{ AtomicBool, AtomicU32, Ordering }, thread };
+use bus::Bus;
+use midir::SendError;
+use midly::{
+    live::LiveEvent,
+    num::u4,
+    Format,
+    Header,
+    MetaMessage,
+    MidiMessage,
+    Smf,
+    Timing,
+    TrackEvent,
+    TrackEventKind,
+};
+
+use crate::midihandler::{ MidiHandlerThread, MidiSink };
+use crate::pitchdetect::{ PitchEstimatorThread, PitchFrame };
+use crate::tuning::Tuning;
+use crate::{ audio, AppArgs, BUS_CAPACITY };
+
+const TICKS_PER_BEAT: u16 = 480;
+const MICROS_PER_BEAT: u32 = 500_000; // 120 bpm, so one tick is a little over a millisecond
+
+// collects the handler's events against the timestamp of the frame that produced them
+struct SmfWriter {
+    time: f64, // microseconds
+    events: Vec<(f64, u4, MidiMessage)>,
+}
+
+impl MidiSink for SmfWriter {
+    fn set_time(&mut self, timestamp: f64) {
+        self.time = timestamp;
+    }
+
+    fn send_event(&mut self, event: LiveEvent) -> Result<(), SendError> {
+        if let LiveEvent::Midi { channel, message } = event {
+            self.events.push((self.time, channel, message));
+        }
+        Ok(())
+    }
+}
+
+impl SmfWriter {
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut track = vec![TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(MICROS_PER_BEAT.into())),
+        }];
+
+        let mut last_tick: u32 = 0;
+        for &(time, channel, message) in self.events.iter() {
+            // clamped so that events can never step back in time
+            let tick = ((time.max(0.0) * (TICKS_PER_BEAT as f64)) / (MICROS_PER_BEAT as f64)).round() as u32;
+            let tick = tick.max(last_tick);
+            track.push(TrackEvent {
+                delta: (tick - last_tick).into(),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+            last_tick = tick;
+        }
+        track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(TICKS_PER_BEAT.into())));
+        smf.tracks.push(track);
+        smf.save(path)
+    }
+}
+
+// runs a wav file through the same pitch and midi threads as the live pipeline, as fast as they will go
+pub fn convert(args: &AppArgs, tuning: &Tuning, input: &Path, output: &Path) -> Result<(), Box<dyn Error>> {
+    let (interleaved, channels, sr) = audio::read_wav(input)?;
+    let routing = args.channel_routing();
+    if routing.num_outputs(channels)? > 1 {
+        return Err("convert writes a single track, use --channel-mode downmix or select".into());
+    }
+    let samples = routing.route(&interleaved, channels).swap_remove(0);
+    let analysis_sr = args.analysis_rate.unwrap_or(sr);
+    let running = Arc::new(AtomicBool::new(true));
+
+    let mut snapshot_bus: Bus<Vec<(f64, f32)>> = Bus::new(BUS_CAPACITY);
+    let pitch_snapshot_rx = snapshot_bus.add_rx();
+    let mut f0_bus: Bus<PitchFrame> = Bus::new(BUS_CAPACITY);
+    let midi_handler_rx = f0_bus.add_rx();
+    let spectrogram_bus: Bus<Vec<f32>> = Bus::new(BUS_CAPACITY); // nobody draws the spectrum offline
+
+    let predictor = args.estimator.build(analysis_sr as f32, args.window, args.bins_per_semitone as usize, args.hmm_lag);
+    let detection_config = args.detection_config();
+    let pitch_running = running.clone();
+    let pitch_thread_handle = thread::Builder
+        ::new()
+        .name("PitchDetectionThread".to_string())
+        .spawn(move || {
+            let mut detector = PitchEstimatorThread::new(
+                predictor,
+                analysis_sr as f32,
+                pitch_snapshot_rx,
+                f0_bus,
+                spectrogram_bus,
+                detection_config,
+                pitch_running
+            );
+            detector.run();
+        })?;
+
+    let reference = Arc::new(AtomicU32::new(tuning.reference().to_bits()));
+    let mut handler = MidiHandlerThread::new(midi_handler_rx, args.midi_config(tuning), reference.clone(), running);
+    let midi_thread_handle = thread::Builder
+        ::new()
+        .name("MidiHandlerThread".to_string())
+        .spawn(move || {
+            let mut writer = SmfWriter { time: 0.0, events: Vec::new() };
+            handler.run_with(&mut writer).map(|_| writer)
+        })?;
+
+    // timestamps come from the sample position, in microseconds like the live stream
+    let mut framer = audio::Framer::new(args.hop_size, sr, analysis_sr);
+    for hop in framer.push(&samples, 0.0).into_iter().chain(framer.flush()) {
+        snapshot_bus.broadcast(hop);
+    }
+    // closing the audio bus lets the pitch thread finish, which in turn closes the pitch bus
+    drop(snapshot_bus);
+
+    pitch_thread_handle.join().expect("Couldn't join pitch thread");
+    let writer = midi_thread_handle.join().expect("Couldn't join midi thread")?;
+    writer.save(output)?;
+
+    println!(
+        "Converted {:.1}s of audio from '{}' into {} MIDI events in '{}'",
+        (samples.len() as f32) / (sr as f32),
+        input.display(),
+        writer.events.len(),
+        output.display()
+    );
+    if args.auto_a4 {
+        println!("Estimated reference: A4 = {:.1} Hz", f32::from_bits(reference.load(Ordering::SeqCst)));
+    }
+    Ok(())
+}
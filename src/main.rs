@@ -1,12 +1,12 @@
-use core::{panic, time};
 use std::{
     error::Error,
     io::{ self, Write },
+    path::PathBuf,
     thread,
     time::{ Duration, Instant },
-    iter::zip,
-    sync::Arc,
-    sync::atomic::{ AtomicBool, Ordering },
+    sync::{ Arc, Mutex },
+    sync::mpsc::TryRecvError,
+    sync::atomic::{ AtomicBool, AtomicU32, Ordering },
 };
 use crossterm::{
     event::{ self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode },
@@ -23,10 +23,13 @@ use tui::{
     Frame,
     Terminal,
 };
-use clap::{Parser, builder::Str};
+use clap::Parser;
 use cpal::{
     traits::{ HostTrait, DeviceTrait, StreamTrait },
+    FromSample,
+    Sample,
     SampleFormat,
+    SizedSample,
     Device,
     SupportedStreamConfigRange,
 };
@@ -34,19 +37,25 @@ use closure::closure;
 use ringbuffer::{ AllocRingBuffer, RingBufferWrite, RingBufferExt };
 use bus::{ Bus, BusReader };
 
+mod audio;
 mod pitchdetect;
 mod midihandler;
-//FIXME: allow for oversized buffer
-const SNAPSHOT_BUFFLEN: usize = 1024; //882;
+mod convert;
+mod tuning;
+mod quantize;
+const SNAPSHOT_BUFFLEN: usize = 1024; // default hop size
 const CONTOUR_BUFFLEN: usize = 128;
+const BUS_CAPACITY: usize = 8; // messages a bus holds before its sender blocks
+const MAX_HMM_LAG: u64 = 64; // hops the smoothed pitch may trail the input by, over a second at the default hop size
+const FILTER_BUDGET: f64 = 150e6; // goertzel filter samples a second one pitch thread keeps up with, about half a core
 
 const MIN_FREQ: f32 = 15.434; //B0
+const MAX_FREQ: f32 = 3729.31; //Bb7, top of the goertzel bank
 const A4: f32 = 440.0;
-const NUM_FREQS: usize = 96;
+const NUM_FREQS: usize = 96; // semitones covered by the goertzel bank
 const NOISE_THRESH: f32 = 100.0;
 
-const BIN_LABELS: [&'static str; NUM_FREQS] = ["_"; NUM_FREQS];
-const NOTE_LABELS: [&'static str; 12] = [
+const NOTE_LABELS: [&str; 12] = [
     "C",
     "C#",
     "D",
@@ -61,80 +70,411 @@ const NOTE_LABELS: [&'static str; 12] = [
     "B",
 ];
 
-fn get_midi_note(frequency: f32) -> u8 {
-    let semitone = 12.0 * f32::log2(frequency / A4) + 69.0;
-    semitone.round() as u8
+fn get_note_label(tuning: &tuning::Tuning, freq: f32) -> &'static str {
+    let midi_idx = tuning.note(freq) % 12;
+    NOTE_LABELS[midi_idx as usize]
 }
 
-fn get_freq(midi_note: u8) -> f32 {
-    let semitone = (midi_note as f32) + 1.0; //MIN_FREQ is B1 not C1 so we compensate
-    let freq = MIN_FREQ * (2.0f32).powf(semitone / 12.0 - 1.0);
-    return freq;
-}
-
-fn get_note_label(freq: f32) -> &'static str {
-    let mut midi_idx = get_midi_note(freq);
-    midi_idx = midi_idx % 12;
-    return NOTE_LABELS[midi_idx as usize];
+// clap parser for --analysis-rate, which has to sample the top of the goertzel bank above nyquist
+fn parse_analysis_rate(rate: &str) -> Result<u32, String> {
+    let rate = rate.parse::<u32>().map_err(|err| err.to_string())?;
+    let min_rate = (2.0 * MAX_FREQ).floor() as u32 + 1;
+    if rate < min_rate {
+        return Err(format!("{} Hz can't represent pitches up to {:.0} Hz, use at least {} Hz", rate, MAX_FREQ, min_rate));
+    }
+    Ok(rate)
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct AppArgs {
-    #[arg(short, long, default_value_t = { "default".to_string() })]
-    device_name: String,
+    /// Input device to open, matched exactly or by a unique case-insensitive substring (prompts if unset)
+    #[arg(short, long)]
+    device_name: Option<String>,
+
+    /// Index of the device's input config to use, as printed by --list-devices (default: the first one fitting --channels and --srate)
+    #[arg(long)]
+    config_index: Option<usize>,
+
+    /// Only consider input configs with this many channels
+    #[arg(long)]
+    channels: Option<u16>,
+
+    /// Ask the device for callbacks of this many frames
+    #[arg(long)]
+    buffer_size: Option<u32>,
 
-    #[arg(short, long, default_value_t = 48000)]
-    srate: usize,
+    /// Print the input devices with their configs and exit
+    #[arg(long, default_value_t = false)]
+    list_devices: bool,
 
+    /// Sample rate to open the input device at, which the chosen config has to support (default: its highest rate). Also the rate of raw PCM on stdin, 48000 if unset
+    #[arg(short, long)]
+    srate: Option<u32>,
+
+    /// Resample the input to this rate before pitch estimation, e.g. 16000 to make the time-domain estimators cheaper
+    #[arg(long, value_parser = parse_analysis_rate)]
+    analysis_rate: Option<u32>,
+
+    /// Voicing threshold on the estimator's 0-1 confidence (clarity for mpm, voiced probability for yin/pyin, strongest filter against the noise floor for goertzel and poly, 0.5 being right at it)
     #[arg(short, long, default_value_t = 0.2)]
-    clairty_thresh: f32,
+    clarity_thresh: f32,
+
+    /// Rise in dB of the input level over the recent average that counts as a new attack, retriggering repeated notes
+    #[arg(long, default_value_t = 6.0)]
+    onset_thresh: f32,
 
     #[arg(short, long, default_value_t = false)]
     no_ui: bool,
+
+    /// Play a WAV file through the pipeline in real time, or read raw mono PCM from stdin with '-', instead of opening an input device
+    #[arg(long)]
+    input: Option<PathBuf>,
+
+    /// Sample format of raw PCM read from stdin
+    #[arg(long, value_enum, default_value_t = audio::PcmFormat::F32)]
+    pcm_format: audio::PcmFormat,
+
+    /// Number of interleaved channels in raw PCM read from stdin
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pcm_channels: u16,
+
+    /// How multichannel input is tracked: mixed down to mono, a single channel picked with --input-channel, or every channel split onto consecutive MIDI channels
+    #[arg(long, value_enum, default_value_t = audio::ChannelMode::Downmix)]
+    channel_mode: audio::ChannelMode,
+
+    /// Input channel tracked by --channel-mode select, counting from 0
+    #[arg(long, default_value_t = 0)]
+    input_channel: usize,
+
+    /// Pitch estimator used by the pitch detection thread
+    #[arg(long, value_enum, default_value_t = pitchdetect::EstimatorKind::Goertzel)]
+    estimator: pitchdetect::EstimatorKind,
+
+    /// Analysis window in samples, by default 32768 for goertzel and poly, 4096 for yin and pyin and 2048 for mpm
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    window: Option<usize>,
+
+    /// Samples between successive pitch estimates, independent of the device's buffer size
+    #[arg(long, default_value_t = SNAPSHOT_BUFFLEN, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    hop_size: usize,
+
+    /// Smooth the goertzel pitch track with an HMM, deciding each frame this many hops later (at most 64)
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(..=MAX_HMM_LAG))]
+    hmm_lag: Option<usize>,
+
+    /// Goertzel filters per semitone, the peak is interpolated between neighbouring filters
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=16))]
+    bins_per_semitone: u16,
+
+    /// How detected pitch is turned into MIDI: retriggered notes, a held note tracked with pitch bend, an MPE controller, or chords (use with --estimator poly)
+    #[arg(long, value_enum, default_value_t = midihandler::MidiMode::Note)]
+    midi_mode: midihandler::MidiMode,
+
+    /// Voiced frames the note segmentation takes the median pitch of
+    #[arg(long, default_value_t = 5, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    median_frames: usize,
+
+    /// Cents past the boundary between two semitones the pitch has to go before the note changes (0-50)
+    #[arg(long, default_value_t = 30.0)]
+    hysteresis_cents: f32,
+
+    /// Milliseconds a new note has to hold before it is sent, shorter blips are dropped
+    #[arg(long, default_value_t = 40.0)]
+    min_note_ms: f64,
+
+    /// Milliseconds of silence before the sounding note is released, bridging short dropouts
+    #[arg(long, default_value_t = 50.0)]
+    release_ms: f64,
+
+    /// Concert pitch in Hz
+    #[arg(long, default_value_t = A4)]
+    a4: f32,
+
+    /// Estimate the reference pitch the performer is tuned to from the last few seconds of notes, starting from --a4, and map notes against it
+    #[arg(long, default_value_t = false)]
+    auto_a4: bool,
+
+    /// Tuning notes are quantised to and bent from, just intonation being relative to --tonic
+    #[arg(long, value_enum, default_value_t = tuning::TuningSystem::Equal)]
+    tuning: tuning::TuningSystem,
+
+    /// Tonic of the just intonation tuning, as a note name such as C, F# or Bb
+    #[arg(long, default_value = "C", value_parser = tuning::parse_pitch_class)]
+    tonic: i32,
+
+    /// Scala scale file (.scl) to tune to instead of --tuning, degree 0 on middle C unless --kbm maps it
+    #[arg(long)]
+    scl: Option<PathBuf>,
+
+    /// Scala keyboard mapping file (.kbm) placing the scale on the MIDI keys and setting its reference frequency
+    #[arg(long)]
+    kbm: Option<PathBuf>,
+
+    /// Scale output notes are kept in, together with --key
+    #[arg(long, value_enum, default_value_t = quantize::Scale::Chromatic)]
+    scale: quantize::Scale,
+
+    /// Key of --scale, as a note name such as C, F# or Bb
+    #[arg(long, default_value = "C", value_parser = tuning::parse_pitch_class)]
+    key: i32,
+
+    /// Custom set of note names output notes are kept to, e.g. C,D,Eb,G,Ab, instead of --scale and --key
+    #[arg(long, value_delimiter = ',', value_parser = tuning::parse_pitch_class)]
+    pitch_classes: Vec<i32>,
+
+    /// Which scale note a note outside the scale moves to
+    #[arg(long, value_enum, default_value_t = quantize::Snap::Nearest)]
+    snap: quantize::Snap,
+
+    /// Pitch bend range in semitones used by the bend and mpe modes
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=24))]
+    bend_range: u8,
+
+    /// Send an RPN 0 message at startup so the synth uses the same bend range
+    #[arg(long, default_value_t = false)]
+    bend_rpn: bool,
+
+    /// How notes are ended: a note-off message, or a note-on with velocity 0
+    #[arg(long, value_enum, default_value_t = midihandler::NoteOffStyle::Message)]
+    note_off: midihandler::NoteOffStyle,
+
+    /// How note-on velocity follows the input's peak level at the start of each note
+    #[arg(long, value_enum, default_value_t = midihandler::VelocityCurve::Log)]
+    velocity_curve: midihandler::VelocityCurve,
+
+    /// Velocity of every note with --velocity-curve fixed
+    #[arg(long, default_value_t = 127, value_parser = clap::value_parser!(u8).range(1..=127))]
+    velocity: u8,
+
+    /// MIDI output to connect to, by index from --list-midi-ports or by name (default: the last one)
+    #[arg(long)]
+    midi_port: Option<String>,
+
+    /// Publish a virtual MIDI output with this name for other applications to subscribe to, instead of connecting to a port
+    #[arg(long, conflicts_with = "midi_port")]
+    virtual_port: Option<String>,
+
+    /// Print the MIDI outputs and exit
+    #[arg(long, default_value_t = false)]
+    list_midi_ports: bool,
+
+    /// MIDI channel (1-16) notes are sent on, with --channel-mode split the first of one channel per input channel
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=16))]
+    midi_channel: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Transcribe a WAV file into a Standard MIDI File, faster than real time
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+    },
+}
+
+impl AppArgs {
+    fn detection_config(&self) -> pitchdetect::DetectionConfig {
+        pitchdetect::DetectionConfig {
+            clarity_thresh: self.clarity_thresh,
+            onset_thresh: self.onset_thresh,
+        }
+    }
+
+    fn tuning(&self) -> Result<tuning::Tuning, Box<dyn Error>> {
+        tuning::Tuning::new(self.a4, self.tuning, self.tonic, self.scl.as_deref(), self.kbm.as_deref())
+    }
+
+    fn midi_config(&self, tuning: &tuning::Tuning) -> midihandler::MidiConfig {
+        midihandler::MidiConfig {
+            mode: self.midi_mode,
+            channel: self.midi_channel - 1,
+            bend_range: self.bend_range,
+            bend_rpn: self.bend_rpn,
+            note_off: self.note_off,
+            velocity_curve: self.velocity_curve,
+            fixed_velocity: self.velocity,
+            median_frames: self.median_frames,
+            hysteresis_cents: self.hysteresis_cents,
+            min_note_ms: self.min_note_ms,
+            release_ms: self.release_ms,
+            tuning: tuning.clone(),
+            auto_reference: self.auto_a4,
+            quantizer: quantize::Quantizer::new(self.scale, self.key, &self.pitch_classes, self.snap),
+        }
+    }
+
+    fn channel_routing(&self) -> audio::ChannelRouting {
+        audio::ChannelRouting {
+            mode: self.channel_mode,
+            channel: self.input_channel,
+        }
+    }
 }
 
 struct App<'a> {
-    waveform_snapshot: [(f32, f32); SNAPSHOT_BUFFLEN],
-    f0_contour: AllocRingBuffer<(f32, f32)>,
+    waveform_snapshot: Vec<(f64, f32)>,
+    f0_contour: AllocRingBuffer<(f64, f32)>,
     spectrogram: Vec<(&'a str, f32)>,
     wavviz_window: [f64; 2],
     f0_window: [f64; 2],
+    confidence: f32, // of the latest pitch frame
+    tuning: tuning::Tuning,
+    reference: Arc<AtomicU32>, // bits of the a4 the midi handler maps notes against
+    running: Arc<AtomicBool>, // cleared by a worker that fails, e.g. when the midi port goes away
 }
 
 impl<'a> App<'a> {
-    fn new() -> App<'a> {
-        let str_freq_arr = BIN_LABELS.into_iter();
-        let init_freq_amps = [0.0f32; NUM_FREQS];
+    fn new(num_bins: usize, tuning: tuning::Tuning, reference: Arc<AtomicU32>, running: Arc<AtomicBool>) -> App<'a> {
         App {
-            waveform_snapshot: [(0.0, 0.0); SNAPSHOT_BUFFLEN],
+            waveform_snapshot: Vec::new(),
             wavviz_window: [0.0, 63555000.0],
             f0_contour: AllocRingBuffer::with_capacity(CONTOUR_BUFFLEN),
-            spectrogram: zip(str_freq_arr, init_freq_amps).collect::<Vec<(&str, f32)>>(),
+            spectrogram: vec![("_", 0.0); num_bins],
             f0_window: [0.0, 63555000.0],
+            confidence: 0.0,
+            tuning,
+            reference,
+            running,
         }
     }
 
     //update window bounds
     fn on_tick(&mut self) {
-        self.wavviz_window[0] = self.waveform_snapshot[0].0 as f64;
-        self.wavviz_window[1] = self.waveform_snapshot[SNAPSHOT_BUFFLEN - 1].0 as f64;
-        self.f0_window[0] = self.f0_contour.get(0).unwrap_or(&(0.0, 0.0)).0 as f64;
-        self.f0_window[1] = self.f0_contour.get(-1).unwrap_or(&(0.0, 0.0)).0 as f64;
+        self.wavviz_window[0] = self.waveform_snapshot.first().unwrap_or(&(0.0, 0.0)).0;
+        self.wavviz_window[1] = self.waveform_snapshot.last().unwrap_or(&(0.0, 0.0)).0;
+        self.f0_window[0] = self.f0_contour.get(0).unwrap_or(&(0.0, 0.0)).0;
+        self.f0_window[1] = self.f0_contour.get(-1).unwrap_or(&(0.0, 0.0)).0;
+        self.tuning.set_reference(f32::from_bits(self.reference.load(Ordering::SeqCst)));
+    }
+}
+
+fn print_configs(configs: &[SupportedStreamConfigRange]) {
+    for (i, c) in configs.iter().enumerate() {
+        println!(
+            "  [{}] {:?}, channels: {}, min_rate: {}, max_rate: {}, buffer_size: {:?}",
+            i,
+            c.sample_format(),
+            c.channels(),
+            c.min_sample_rate().0,
+            c.max_sample_rate().0,
+            c.buffer_size()
+        );
+    }
+}
+
+// the device and config table behind --device-name and --config-index
+fn list_devices() -> Result<(), Box<dyn Error>> {
+    let host = cpal::default_host();
+    let devices = host.input_devices()?.collect::<Vec<Device>>();
+    if devices.is_empty() {
+        println!("No input devices available");
+    }
+    for (i, device) in devices.iter().enumerate() {
+        println!("[{}] {}", i, device.name().unwrap_or("<Unknown>".to_string()));
+        match device.supported_input_configs() {
+            Ok(configs) => print_configs(&configs.collect::<Vec<SupportedStreamConfigRange>>()),
+            Err(err) => println!("  couldn't query configs: {}", err),
+        }
+    }
+    Ok(())
+}
+
+// an exact name wins, otherwise the query has to be a case-insensitive substring of exactly one name
+fn match_name(names: &[String], query: &str, kind: &str, list_flag: &str) -> Result<usize, Box<dyn Error>> {
+    if let Some(idx) = names.iter().position(|n| n == query) {
+        return Ok(idx);
     }
+    let matches = (0..names.len())
+        .filter(|&i| names[i].to_lowercase().contains(&query.to_lowercase()))
+        .collect::<Vec<usize>>();
+    match matches.as_slice() {
+        [idx] => Ok(*idx),
+        [] => Err(format!("no {} matches '{}', see {}", kind, query, list_flag).into()),
+        _ => {
+            let candidates = matches
+                .iter()
+                .map(|&i| names[i].as_str())
+                .collect::<Vec<&str>>();
+            Err(format!("'{}' matches several {}s: {}", query, kind, candidates.join(", ")).into())
+        }
+    }
+}
+
+fn find_device(devices: &[Device], name: &str) -> Result<Device, Box<dyn Error>> {
+    let names = devices
+        .iter()
+        .map(|d| d.name().unwrap_or_default())
+        .collect::<Vec<String>>();
+    let idx = match_name(&names, name, "input device", "--list-devices")?;
+    Ok(devices[idx].clone())
 }
 
-fn select_device_and_config() -> Result<(Device, cpal::SupportedStreamConfig), Box<dyn Error>> {
-    // setup audio stream - interactive device & config selection
+// prompts for whatever the command line leaves open, so scripts that name a device never block on stdin
+fn select_device_and_config(args: &AppArgs) -> Result<(Device, cpal::SupportedStreamConfig), Box<dyn Error>> {
     let host = cpal::default_host();
 
     // gather input devices
     let devices = host
         .input_devices()
         .expect("No input devices available")
-        .into_iter()
         .collect::<Vec<Device>>();
 
+    let device = match &args.device_name {
+        Some(name) => find_device(&devices, name)?,
+        None => prompt_device(&host, &devices)?,
+    };
+
+    println!("Selected device: {}", device.name().unwrap_or("<Unknown>".to_string()));
+
+    // list supported configs for chosen device
+    let configs = device
+        .supported_input_configs()
+        .expect("error while querying configs")
+        .collect::<Vec<SupportedStreamConfigRange>>();
+
+    let fits = |c: &SupportedStreamConfigRange| {
+        args.channels.is_none_or(|channels| c.channels() == channels) &&
+            args.srate.is_none_or(|rate| c.min_sample_rate().0 <= rate && rate <= c.max_sample_rate().0)
+    };
+    let idx = match args.config_index {
+        Some(idx) => {
+            let config = configs.get(idx).ok_or(format!("there is no config [{}], see --list-devices", idx))?;
+            if let Some(channels) = args.channels.filter(|&channels| config.channels() != channels) {
+                return Err(format!("config [{}] has {} channels, not {}", idx, config.channels(), channels).into());
+            }
+            idx
+        }
+        None if args.device_name.is_none() && args.channels.is_none() => prompt_config(&device, &configs)?,
+        None =>
+            configs
+                .iter()
+                .position(fits)
+                .ok_or("no config of this device matches --channels and --srate, see --list-devices")?,
+    };
+
+    let config_range = configs.into_iter().nth(idx).expect("Invalid Stream Config");
+    if let (Some(size), cpal::SupportedBufferSize::Range { min, max }) = (args.buffer_size, config_range.buffer_size()) {
+        if size < *min || size > *max {
+            return Err(format!("config [{}] takes buffers of {}-{} frames, not {}", idx, min, max, size).into());
+        }
+    }
+    let (min_rate, max_rate) = (config_range.min_sample_rate().0, config_range.max_sample_rate().0);
+    let supported_config = match args.srate {
+        Some(rate) if rate < min_rate || rate > max_rate => {
+            return Err(format!("config [{}] runs at {}-{} Hz, it can't open at the requested {} Hz", idx, min_rate, max_rate, rate).into());
+        }
+        Some(rate) => config_range.with_sample_rate(cpal::SampleRate(rate)),
+        None => config_range.with_max_sample_rate(),
+    };
+    println!("Sample rate: {} Hz", supported_config.sample_rate().0);
+    Ok((device, supported_config))
+}
+
+fn prompt_device(host: &cpal::Host, devices: &[Device]) -> Result<Device, Box<dyn Error>> {
     println!("Available input devices:");
     for (i, d) in devices.iter().enumerate() {
         println!("  [{}] {}", i, d.name().unwrap_or("<Unknown>".to_string()));
@@ -167,28 +507,12 @@ fn select_device_and_config() -> Result<(Device, cpal::SupportedStreamConfig), B
         };
         break;
     }
+    Ok(device)
+}
 
-    println!("Selected device: {}", device.name().unwrap_or("<Unknown>".to_string()));
-
-    // list supported configs for chosen device
-    let configs = device
-        .supported_input_configs()
-        .expect("error while querying configs")
-        .into_iter()
-        .collect::<Vec<SupportedStreamConfigRange>>();
-
+fn prompt_config(device: &Device, configs: &[SupportedStreamConfigRange]) -> Result<usize, Box<dyn Error>> {
     println!("Supported input configs for '{}':", device.name().unwrap_or("<Unknown>".to_string()));
-    for (i, c) in configs.iter().enumerate() {
-        println!(
-            "  [{}] {:?}, channels: {}, min_rate: {}, max_rate: {}, buffer_size: {:?}",
-            i,
-            c.sample_format(),
-            c.channels(),
-            c.min_sample_rate().0,
-            c.max_sample_rate().0,
-            c.buffer_size()
-        );
-    }
+    print_configs(configs);
 
     // prompt user to select config (press Enter to choose first config)
     let idx: usize = loop {
@@ -217,91 +541,244 @@ fn select_device_and_config() -> Result<(Device, cpal::SupportedStreamConfig), B
             }
         }
     };
-    
-    let supported_config = configs.into_iter().nth(idx).expect("Invalid Stream Config").with_max_sample_rate();
-    Ok((device, supported_config))
+    Ok(idx)
+}
+
+// where the live pipeline's audio comes from
+enum AudioInput {
+    Device(Device, cpal::SupportedStreamConfig),
+    Wav {
+        samples: Vec<f32>,
+        channels: usize,
+        sr: u32,
+    },
+    Stdin,
+}
+
+// any device sample format is normalised to f32 in [-1, 1] before it reaches the snapshot buses
+fn build_input_stream<T>(
+    device: &Device,
+    config: &cpal::StreamConfig,
+    routing: audio::ChannelRouting,
+    framers: Vec<audio::Framer>,
+    snapshot_buses: Vec<Bus<Vec<(f64, f32)>>>,
+    err_fn: fn(cpal::StreamError)
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where T: SizedSample, f32: FromSample<T>
+{
+    // init timing vars
+    let origin: Option<cpal::StreamInstant> = None;
+    let frames_read: u64 = 0;
+    let channels = config.channels as usize;
+    let sr = config.sample_rate.0;
+
+    device.build_input_stream(
+        config,
+        closure!(move mut origin, move mut frames_read, move mut snapshot_buses, move mut framers, move routing, move channels, move sr, |input:&[T], info: &cpal::InputCallbackInfo| {
+            // the first buffer's capture instant is time zero and each later buffer starts at its own capture
+            // instant, so dropped buffers show up as gaps. should the backend's clock ever step back,
+            // the running sample count takes over
+            let capture = info.timestamp().capture;
+            let origin = *origin.get_or_insert(capture);
+            let start_time = capture
+                .duration_since(&origin)
+                .map(|elapsed| elapsed.as_secs_f64() * 1e6)
+                .unwrap_or_else(|| audio::sample_time(frames_read, sr));
+
+            let samples = input.iter().map(|&s| f32::from_sample(s)).collect::<Vec<f32>>();
+            let streams = routing.route(&samples, channels);
+            for ((snapshot_bus, framer), stream) in snapshot_buses.iter_mut().zip(framers.iter_mut()).zip(streams) {
+                for hop in framer.push(&stream, start_time) {
+                    snapshot_bus.broadcast(hop);
+                }
+            }
+            frames_read += (input.len() / channels.max(1)) as u64;
+        }),
+        err_fn,
+        None
+    )
+}
+
+// opens the selected input device, broadcasting each routed channel on its own snapshot bus
+fn open_input_stream(
+    device: Device,
+    supported_config: cpal::SupportedStreamConfig,
+    buffer_size: Option<u32>,
+    routing: audio::ChannelRouting,
+    framers: Vec<audio::Framer>,
+    snapshot_buses: Vec<Bus<Vec<(f64, f32)>>>
+) -> Result<cpal::Stream, Box<dyn Error>> {
+    let err_fn: fn(cpal::StreamError) = |err| eprintln!("an error occurred on the output audio stream: {}", err);
+    let sample_format = supported_config.sample_format();
+    let mut config: cpal::StreamConfig = supported_config.into();
+    if let Some(frames) = buffer_size {
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+
+    let stream = match sample_format {
+        SampleFormat::I8 => build_input_stream::<i8>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::I32 => build_input_stream::<i32>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::I64 => build_input_stream::<i64>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::U8 => build_input_stream::<u8>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::U32 => build_input_stream::<u32>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::U64 => build_input_stream::<u64>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        SampleFormat::F64 => build_input_stream::<f64>(&device, &config, routing, framers, snapshot_buses, err_fn),
+        sample_format => {
+            return Err(format!("Unsupported sample format '{sample_format}'").into());
+        }
+    }?;
+    Ok(stream)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = AppArgs::parse();
+    if args.hmm_lag.is_some() && !matches!(args.estimator, pitchdetect::EstimatorKind::Goertzel) {
+        return Err("--hmm-lag smooths the goertzel filter bank, use it with --estimator goertzel".into());
+    }
 
-    let (device, supported_config) = select_device_and_config()?;
+    let tuning = args.tuning()?;
 
-    let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
-    let sample_format = supported_config.sample_format();
-    let config = supported_config.into();
+    if let Some(Command::Convert { input, output }) = &args.command {
+        return convert::convert(&args, &tuning, input, output);
+    }
+    if args.list_devices {
+        return list_devices();
+    }
+    if args.list_midi_ports {
+        return midihandler::list_ports();
+    }
 
     let running = Arc::new(AtomicBool::new(true));
 
-    //establish channel
-    let mut snapshot_bus: Bus<[(f32, f32); SNAPSHOT_BUFFLEN]> = Bus::new(8);
-    let pitch_snapshot_rx = snapshot_bus.add_rx();
-    let wavviz_snapshot_rx = snapshot_bus.add_rx();
+    let input = match &args.input {
+        Some(path) if path.as_os_str() == "-" => AudioInput::Stdin,
+        Some(path) => {
+            let (samples, channels, sr) = audio::read_wav(path)?;
+            AudioInput::Wav { samples, channels, sr }
+        }
+        None => {
+            let (device, supported_config) = select_device_and_config(&args)?;
+            AudioInput::Device(device, supported_config)
+        }
+    };
 
-    // init timing vars
-    let prev_time = Instant::now();
-    let time = 0.0;
-    
-    //let audio_thread_snapshot_ref:Arc<Mutex<[(f32, f32); SNAPSHOT_BUFFLEN]>> = Arc::clone(&snapshot);
-    let stream = (
-        match sample_format {
-            SampleFormat::F32 =>
-                device.build_input_stream(
-                    &config,
-                    closure!(move mut time, move mut prev_time, move mut snapshot_bus, |input:&[f32], _callbackdata| {
-                //FIXME: detect multiple channels interleaved
-                let timediff = (Instant::now().duration_since(prev_time)).as_micros() as f32;
-
-                let mut out:[(f32, f32);SNAPSHOT_BUFFLEN] = [(0.0,0.0); SNAPSHOT_BUFFLEN];
-                let max_idx = std::cmp::min(SNAPSHOT_BUFFLEN, input.len());
-                for i in 0..max_idx - 1 {
-                    let t = time + ((i+1) as f32 *timediff) as f32;
-                    out[i] = (t, input[i]); // create tuple of timestamp with each sample
-                } 
-                snapshot_bus.broadcast(out);
-                time += timediff;
-                prev_time = Instant::now();
-            }),
-                    err_fn,
-                    None
-                ),
-            sample_format => panic!("Unsupported sample format '{sample_format}'"),
+    // devices report the rate they were opened at and a wav file brings its own, stdin runs at --srate
+    let (sr, channels) = match &input {
+        AudioInput::Device(_, supported_config) => (supported_config.sample_rate().0, supported_config.channels() as usize),
+        AudioInput::Wav { channels, sr, .. } => (*sr, *channels),
+        AudioInput::Stdin => (args.srate.unwrap_or(48000), args.pcm_channels as usize),
+    };
+    let analysis_sr = args.analysis_rate.unwrap_or(sr);
+    let routing = args.channel_routing();
+    let num_pipelines = routing.num_outputs(channels)?;
+    if num_pipelines > 1 && args.midi_mode == midihandler::MidiMode::Mpe {
+        return Err("mpe output already uses every MIDI channel, it can't be combined with --channel-mode split".into());
+    }
+    if (args.midi_channel as usize) + num_pipelines - 1 > 16 {
+        return Err(format!("{} channels starting at MIDI channel {} don't fit in 16 MIDI channels", num_pipelines, args.midi_channel).into());
+    }
+    // a pitch thread that falls behind stalls the audio callback, convert has no deadline so it's left alone
+    if let Some(work) = args.estimator.filter_samples(analysis_sr as f32, args.window, args.bins_per_semitone as usize) {
+        let min_hop = ((work as f64) * (analysis_sr as f64) / FILTER_BUDGET).ceil() as usize;
+        if args.hop_size < min_hop {
+            return Err(
+                format!(
+                    "{} bins per semitone at {} Hz can't keep up with a hop of {} samples, use --hop-size {} or more, fewer --bins-per-semitone or a lower --analysis-rate",
+                    args.bins_per_semitone,
+                    analysis_sr,
+                    args.hop_size,
+                    min_hop
+                ).into()
+            );
         }
-    ).unwrap();
-    stream.play().unwrap(); // run in new thread
-
-    // establish commincation lines to pitch estimator thread
-    let mut f0_bus: Bus<(f32, f32, bool, f32)> = Bus::new(8);
-    let freqviz_rx = f0_bus.add_rx();
-    let midi_handler_rx = f0_bus.add_rx();
-
-    let mut spectrogram_bus: Bus<[f32; NUM_FREQS]> = Bus::new(8);
-    let spectrogram_rx = spectrogram_bus.add_rx();
-
-    let sr = args.srate.clone();
-    let cthresh = args.clairty_thresh.clone();
-
-    let pitch_running = running.clone();
-    let pitch_thread_handle = thread::Builder
-        ::new()
-        .name("PitchDetectionThread".to_string())
-        .spawn(
-            closure!(move sr,  move cthresh, move pitch_snapshot_rx, move mut f0_bus, move mut spectrogram_bus, move pitch_running, || {
-                let mut detector = pitchdetect::PitchEstimatorThread::new(sr, pitch_snapshot_rx, f0_bus, spectrogram_bus, cthresh, pitch_running);
-                detector.run();
+    }
+
+    // opened up front so a missing port is reported before the ui takes over the terminal
+    let midi_connection = match &args.virtual_port {
+        Some(name) => midihandler::create_virtual_port(name)?,
+        None => midihandler::connect(args.midi_port.as_deref())?,
+    };
+    let midi_output = Arc::new(Mutex::new(midi_connection));
+
+    //establish channels, one pitch and midi thread per tracked stream
+    let mut snapshot_buses: Vec<Bus<Vec<(f64, f32)>>> = (0..num_pipelines).map(|_| Bus::new(BUS_CAPACITY)).collect();
+    let mut f0_buses: Vec<Bus<pitchdetect::PitchFrame>> = (0..num_pipelines).map(|_| Bus::new(BUS_CAPACITY)).collect();
+    let mut spectrogram_buses: Vec<Bus<Vec<f32>>> = (0..num_pipelines).map(|_| Bus::new(BUS_CAPACITY)).collect();
+
+    // the ui follows the first stream
+    let wavviz_snapshot_rx = snapshot_buses[0].add_rx();
+    let freqviz_rx = f0_buses[0].add_rx();
+    let spectrogram_rx = spectrogram_buses[0].add_rx();
+
+    let references = (0..num_pipelines).map(|_| Arc::new(AtomicU32::new(tuning.reference().to_bits()))).collect::<Vec<_>>();
+
+    let mut pitch_thread_handles = Vec::new();
+    let mut midi_thread_handles = Vec::new();
+    for (pipeline, (mut f0_bus, spectrogram_bus)) in f0_buses.into_iter().zip(spectrogram_buses).enumerate() {
+        // establish commincation lines to pitch estimator thread
+        let pitch_snapshot_rx = snapshot_buses[pipeline].add_rx();
+        let midi_handler_rx = f0_bus.add_rx();
+
+        let detection_config = args.detection_config();
+        let predictor = args.estimator.build(analysis_sr as f32, args.window, args.bins_per_semitone as usize, args.hmm_lag);
+
+        let pitch_running = running.clone();
+        let pitch_thread_handle = thread::Builder
+            ::new()
+            .name("PitchDetectionThread".to_string())
+            .spawn(
+                closure!(move predictor, move analysis_sr, move detection_config, move pitch_snapshot_rx, move f0_bus, move spectrogram_bus, move pitch_running, || {
+                    let mut detector = pitchdetect::PitchEstimatorThread::new(predictor, analysis_sr as f32, pitch_snapshot_rx, f0_bus, spectrogram_bus, detection_config, pitch_running);
+                    detector.run();
+                })
+            )
+            .unwrap();
+        pitch_thread_handles.push(pitch_thread_handle);
+
+        let mut midi_config = args.midi_config(&tuning);
+        midi_config.channel += pipeline as u8;
+        let midi_running = running.clone();
+        let reference = references[pipeline].clone();
+        let mut midi_output = midi_output.clone();
+        let midi_thread_handle = thread::Builder
+            ::new()
+            .name("MidiHandlerThread".to_string())
+            .spawn(move || {
+                let mut handler = midihandler::MidiHandlerThread::new(midi_handler_rx, midi_config, reference, midi_running.clone());
+                let result = handler.run_with(&mut midi_output);
+                // losing the port stops every thread, so main can report it once the terminal is restored
+                if result.is_err() {
+                    midi_running.store(false, Ordering::SeqCst);
+                }
+                result
             })
-        )
-        .unwrap();
-
-    let midi_running = running.clone();
-    let midi_thread_handle = thread::Builder
-        ::new()
-        .name("MidiHandlerThread".to_string())
-        .spawn(move || {
-            let mut handler = midihandler::MidiHandlerThread::new(midi_handler_rx, midi_running);
-            handler.run();
-        })
-        .unwrap();
+            .unwrap();
+        midi_thread_handles.push(midi_thread_handle);
+    }
+
+    // hops are cut at the analysis rate, whatever rate the source runs at
+    let framers = (0..num_pipelines)
+        .map(|_| audio::Framer::new(args.hop_size, sr, analysis_sr))
+        .collect::<Vec<audio::Framer>>();
+    let stream = match input {
+        AudioInput::Device(device, supported_config) => {
+            let stream = open_input_stream(device, supported_config, args.buffer_size, routing, framers, snapshot_buses)?;
+            stream.play().unwrap(); // run in new thread
+            Some(stream)
+        }
+        AudioInput::Wav { samples, channels, sr } => {
+            audio::spawn_source(samples.into_iter(), channels, sr, routing, framers, snapshot_buses, running.clone())?;
+            None
+        }
+        AudioInput::Stdin => {
+            let samples = audio::stdin_samples(args.pcm_format, running.clone())?;
+            audio::spawn_source(samples, channels, sr, routing, framers, snapshot_buses, running.clone())?;
+            None
+        }
+    };
 
     // setup terminal
     enable_raw_mode()?;
@@ -312,7 +789,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // create app and run it
     let tick_rate = Duration::from_millis(1);
-    let app = App::new();
+    let app = App::new(NUM_FREQS * (args.bins_per_semitone as usize), tuning, references[0].clone(), running.clone());
     run_app(
         &mut terminal,
         app,
@@ -328,11 +805,18 @@ fn main() -> Result<(), Box<dyn Error>> {
     execute!(terminal.backend_mut(), LeaveAlternateScreen, DisableMouseCapture)?;
     terminal.show_cursor()?;
     
-    stream.pause().unwrap();
-    
     running.store(false, Ordering::SeqCst);
-    pitch_thread_handle.join().expect("Couldn't join pitch thread");
-    midi_thread_handle.join().expect("Couldn't join pitch thread");
+    // dropping the stream closes its snapshot buses, which wakes the pitch threads up to exit
+    drop(stream);
+    for pitch_thread_handle in pitch_thread_handles {
+        pitch_thread_handle.join().expect("Couldn't join pitch thread");
+    }
+    for midi_thread_handle in midi_thread_handles {
+        midi_thread_handle
+            .join()
+            .expect("Couldn't join midi thread")
+            .map_err(|err| format!("MIDI output failed: {}", err))?;
+    }
     
     Ok(())
 }
@@ -341,24 +825,50 @@ fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
     mut app: App,
     tick_rate: Duration,
-    mut snapshot_rx: BusReader<[(f32, f32); SNAPSHOT_BUFFLEN]>,
-    mut contour_rx: BusReader<(f32, f32, bool, f32)>,
-    mut spectrogram_rx: BusReader<[f32; NUM_FREQS]>,
+    mut snapshot_rx: BusReader<Vec<(f64, f32)>>,
+    mut contour_rx: BusReader<pitchdetect::PitchFrame>,
+    mut spectrogram_rx: BusReader<Vec<f32>>,
     render_ui: bool
 ) -> io::Result<()> {
     let mut last_tick = Instant::now();
 
     loop {
+        if !app.running.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
         // wait for new audio frame
-        app.waveform_snapshot = snapshot_rx.recv().unwrap();
+        // a file or pipe source closes its bus once the stream runs out
+        app.waveform_snapshot = match snapshot_rx.recv() {
+            Ok(snapshot) => snapshot,
+            Err(_) => return Ok(()),
+        };
 
-        // attempt to read new freq frame, if fail: use previous values
-        let (timestamp, f0, voiced, _vprob) = contour_rx.recv().unwrap();
-        app.f0_contour.push((timestamp, if voiced { f0 } else { 0.0f32 }));
+        // attempt to read new freq frames, if fail: use previous values. the pitch thread sends nothing
+        // until its window fills, so waiting for a frame per snapshot would stall the audio buses
+        loop {
+            match contour_rx.try_recv() {
+                Ok(frame) => {
+                    app.f0_contour.push((frame.timestamp, if frame.voiced { frame.f0 } else { 0.0f32 }));
+                    app.confidence = frame.confidence;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
 
-        let specdata = spectrogram_rx.recv().unwrap();
-        for i in 0..NUM_FREQS {
-            app.spectrogram[i].1 = specdata[i];
+        let mut specdata = None;
+        loop {
+            match spectrogram_rx.try_recv() {
+                Ok(latest) => specdata = Some(latest),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        if let Some(specdata) = specdata {
+            for (i, bar) in app.spectrogram.iter_mut().enumerate() {
+                bar.1 = specdata.get(i).copied().unwrap_or(0.0);
+            }
         }
 
         // render ui
@@ -394,15 +904,16 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
         .split(size);
 
     let mut bardata_float = app.spectrogram.clone();
+    let bins_per_semitone = (bardata_float.len() / NUM_FREQS).max(1);
     // supress noise, display all zeros if total strength is less than a tenth of the noise thresh
     if
         bardata_float
             .iter()
             .map(|el| el.1)
-            .sum::<f32>() < (NOISE_THRESH * (NUM_FREQS as f32)) / 25.0
+            .sum::<f32>() / (bins_per_semitone as f32) < (NOISE_THRESH * (NUM_FREQS as f32)) / 25.0
     {
-        for i in 0..NUM_FREQS {
-            bardata_float[i].1 = 0.0;
+        for bar in bardata_float.iter_mut() {
+            bar.1 = 0.0;
         }
     }
     let bardata_u64: Vec<(&str, u64)> = bardata_float
@@ -413,7 +924,7 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
         .block(Block::default().title("Spectrogram").borders(Borders::ALL))
         .data(bardata_u64.as_slice())
         .bar_width(1)
-        .bar_gap(1)
+        .bar_gap(if bins_per_semitone > 1 { 0 } else { 1 }) // squeeze sub-semitone bins together
         .bar_style(Style::default().fg(Color::White))
         .value_style(Style::default().bg(Color::White).add_modifier(Modifier::BOLD));
 
@@ -437,7 +948,7 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
     ];
     let wav_data = app.waveform_snapshot
         .iter()
-        .map(|&e| (e.0 as f64, e.1 as f64))
+        .map(|&e| (e.0, e.1 as f64))
         .collect::<Vec<(f64, f64)>>();
     let wav_datavec = vec![
         Dataset::default()
@@ -481,7 +992,7 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
 
     let mut f0_data = app.f0_contour
         .iter()
-        .map(|&e| (e.0 as f64, e.1 as f64))
+        .map(|&e| (e.0, e.1 as f64))
         .collect::<Vec<(f64, f64)>>()
         .clone();
     f0_data.reverse();
@@ -505,7 +1016,12 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
             Block::default()
                 .title(
                     Span::styled(
-                        get_note_label(f0_data[0].1 as f32),
+                        format!(
+                            "{}  confidence {:.2}  A4 = {:.1} Hz",
+                            get_note_label(&app.tuning, f0_data.first().map_or(0.0, |e| e.1) as f32),
+                            app.confidence,
+                            app.tuning.reference()
+                        ),
                         Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)
                     )
                 )
@@ -1,90 +1,480 @@
 use bus::BusReader;
-use midir::MidiOutputConnection;
-use midly::{ live::LiveEvent, MidiMessage };
-use ringbuffer::{ AllocRingBuffer, RingBufferExt, RingBufferWrite };
-use std::sync::Arc;
-use std::sync::atomic::{ AtomicBool, Ordering };
+use midir::{ MidiOutput, MidiOutputConnection, SendError };
+use midly::{ live::LiveEvent, MidiMessage, PitchBend };
+use ringbuffer::{ AllocRingBuffer, RingBuffer, RingBufferExt, RingBufferWrite };
+use std::error::Error;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicU32, Ordering };
 
-use crate::get_midi_note;
+use crate::match_name;
+use crate::pitchdetect::PitchFrame;
+use crate::quantize::Quantizer;
+use crate::tuning::{ ReferenceEstimator, Tuning };
+mod segment;
 
-const BUFFER_CAP: u8 = 8;
+const ONSET_LEVEL_FRAMES: usize = 4; // velocity comes from the loudest peak among this many recent frames, ringbuffer needs a power of two
+const POLY_VOTE_FRAMES: usize = 4; // a chord note sounds while detected in most of this many frames, ringbuffer needs a power of two
+
+const CC_RPN_MSB: u8 = 101;
+const CC_RPN_LSB: u8 = 100;
+const CC_DATA_ENTRY_MSB: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_BRIGHTNESS: u8 = 74; // mpe timbre dimension
+const RPN_NULL: u8 = 127;
+const RPN_PITCH_BEND_SENSITIVITY: u8 = 0;
+const RPN_MPE_CONFIGURATION: u8 = 6;
+
+// lower zone: channel 1 is the manager, channels 2-16 carry one note each
+const MPE_MANAGER_CHANNEL: u8 = 0;
+const MPE_FIRST_MEMBER: u8 = 1;
+const MPE_MEMBER_COUNT: u8 = 15;
+
+const PRESSURE_FLOOR_DB: f32 = -60.0; // input level mapped to zero channel pressure
+const VELOCITY_FLOOR_DB: f32 = -48.0; // peak level mapped to the softest velocity by the log curve
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MidiMode {
+    // retrigger a note on every semitone change
+    Note,
+    // hold a note and follow the performer's intonation with pitch bend
+    Bend,
+    // mpe lower zone: per-note bend, pressure from level and CC74 from brightness, notes rotate over channels 2-16
+    Mpe,
+    // follow the set of simultaneous notes from a polyphonic estimator
+    Poly,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum NoteOffStyle {
+    // a real note-off message (0x80)
+    Message,
+    // note-on with velocity 0, which keeps running status going on a serial link
+    ZeroVelocity,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum VelocityCurve {
+    // velocity proportional to the peak sample level
+    Linear,
+    // velocity proportional to the peak level in dB, closer to how loudness is heard
+    Log,
+    // every note uses the same velocity
+    Fixed,
+}
+
+pub struct MidiConfig {
+    pub mode: MidiMode,
+    pub channel: u8, // counting from 0, mpe always uses the lower zone
+    pub bend_range: u8, // semitones either side of the held key
+    pub bend_rpn: bool, // announce bend_range to the synth with RPN 0 at startup, always done in mpe mode
+    pub note_off: NoteOffStyle,
+    pub velocity_curve: VelocityCurve,
+    pub fixed_velocity: u8, // used by VelocityCurve::Fixed
+    pub median_frames: usize, // voiced frames the note segmentation takes the median pitch of
+    pub hysteresis_cents: f32, // how far past a semitone boundary the pitch goes before the note changes
+    pub min_note_ms: f64, // a new note has to hold this long before it sounds
+    pub release_ms: f64, // silence has to last this long before the note is released
+    pub tuning: Tuning, // keys are chosen, and bent from, by where they sound in this tuning
+    pub auto_reference: bool, // follow the a4 the performer turns out to be tuned to
+    pub quantizer: Quantizer, // keeps notes in a scale
+}
+
+impl MidiConfig {
+    // maps the peak level around an onset to a note-on velocity, never 0 since that would end the note
+    fn velocity(&self, peak: f32) -> u8 {
+        let velocity = match self.velocity_curve {
+            VelocityCurve::Linear => peak * 127.0,
+            VelocityCurve::Log => {
+                let db = 20.0 * peak.max(f32::EPSILON).log10();
+                ((db - VELOCITY_FLOOR_DB) / -VELOCITY_FLOOR_DB) * 127.0
+            }
+            VelocityCurve::Fixed => self.fixed_velocity as f32,
+        };
+        velocity.round().clamp(1.0, 127.0) as u8
+    }
+}
+
+// destination for the handler's events, either a live port or a file being written
+pub trait MidiSink {
+    // timestamp of the pitch frame the following events respond to
+    fn set_time(&mut self, _timestamp: f64) {}
+
+    fn send_event(&mut self, event: LiveEvent) -> Result<(), SendError>;
+}
+
+// one port connection shared by the handlers of every tracked channel
+impl MidiSink for Arc<Mutex<MidiOutputConnection>> {
+    fn send_event(&mut self, event: LiveEvent) -> Result<(), SendError> {
+        let mut live_buffer = Vec::new();
+        event.write(&mut live_buffer).unwrap();
+        self.lock().unwrap().send(&live_buffer[..])
+    }
+}
+
+fn midi_client() -> Result<MidiOutput, Box<dyn Error>> {
+    MidiOutput::new("pitch2synth").map_err(|err| format!("couldn't open the MIDI system: {}", err).into())
+}
+
+pub fn list_ports() -> Result<(), Box<dyn Error>> {
+    let midi_out = midi_client()?;
+    let ports = midi_out.ports();
+    if ports.is_empty() {
+        println!("No MIDI outputs available");
+    }
+    for (i, port) in ports.iter().enumerate() {
+        println!("[{}] {}", i, midi_out.port_name(port).unwrap_or("<Unknown>".to_string()));
+    }
+    Ok(())
+}
+
+// publishes a port that daws and soft-synths can subscribe to, rather than connecting to an existing one
+#[cfg(unix)]
+pub fn create_virtual_port(name: &str) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    use midir::os::unix::VirtualOutput;
+    let connection = midi_client()?
+        .create_virtual(name)
+        .map_err(|err| format!("couldn't create virtual MIDI port '{}': {}", name, err))?;
+    println!("MIDI output: virtual port '{}'", name);
+    Ok(connection)
+}
+
+#[cfg(not(unix))]
+pub fn create_virtual_port(_name: &str) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    Err("virtual MIDI ports are only supported with ALSA and CoreMIDI".into())
+}
+
+// port is an index from --list-midi-ports or a name, without one the last port is used
+pub fn connect(port: Option<&str>) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    let midi_out = midi_client()?;
+    let ports = midi_out.ports();
+    if ports.is_empty() {
+        return Err("couldn't find any midi outputs".into());
+    }
+    let names = ports
+        .iter()
+        .map(|port| midi_out.port_name(port).unwrap_or_default())
+        .collect::<Vec<String>>();
+    let idx = match port {
+        None => ports.len() - 1,
+        Some(choice) =>
+            match choice.parse::<usize>() {
+                Ok(idx) if idx < ports.len() => idx,
+                Ok(idx) => {
+                    return Err(format!("there is no MIDI output [{}], see --list-midi-ports", idx).into());
+                }
+                Err(_) => match_name(&names, choice, "MIDI output", "--list-midi-ports")?,
+            }
+    };
+    println!("MIDI output: {}", names[idx]);
+    midi_out
+        .connect(&ports[idx], "pitch2synth")
+        .map_err(|err| format!("couldn't connect to '{}': {}", names[idx], err).into())
+}
 
 pub struct MidiHandlerThread {
-    freq_rx: BusReader<(f32, f32, bool, f32)>,
-    buffer: AllocRingBuffer<f32>,
+    freq_rx: BusReader<PitchFrame>,
+    segmenter: segment::NoteSegmenter,
+    config: MidiConfig,
+    reference: Arc<AtomicU32>, // bits of the a4 notes are currently mapped against, for the ui
     running: Arc<AtomicBool>,
 }
 
-fn note_swap(channel: u8, key: u8, on: bool) -> LiveEvent<'static> {
-    let ev = midly::live::LiveEvent::Midi {
+// the monophonic modes are either silent or holding exactly one note
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NoteState {
+    Idle,
+    Sounding { channel: u8, key: u8 },
+}
+
+fn note_on(channel: u8, key: u8, velocity: u8) -> LiveEvent<'static> {
+    midly::live::LiveEvent::Midi {
         channel: channel.into(),
-        message: match on {
-            true =>
-                MidiMessage::NoteOn {
+        message: MidiMessage::NoteOn {
+            key: key.into(),
+            vel: velocity.into(),
+        },
+    }
+}
+
+fn note_off(channel: u8, key: u8, style: NoteOffStyle) -> LiveEvent<'static> {
+    midly::live::LiveEvent::Midi {
+        channel: channel.into(),
+        message: match style {
+            NoteOffStyle::Message =>
+                MidiMessage::NoteOff {
                     key: key.into(),
-                    vel: (127).into(),
+                    vel: (0).into(),
                 },
-            false =>
+            NoteOffStyle::ZeroVelocity =>
                 MidiMessage::NoteOn {
                     key: key.into(),
                     vel: (0).into(),
                 },
         },
-    };
-    ev
+    }
 }
 
-fn send_live_message(curr_note: &u8, last_note: u8, output: &mut MidiOutputConnection) {
-    let mut live_buffer = Vec::new();
+fn pitch_bend(channel: u8, bend: PitchBend) -> LiveEvent<'static> {
+    midly::live::LiveEvent::Midi {
+        channel: channel.into(),
+        message: MidiMessage::PitchBend { bend },
+    }
+}
 
-    note_swap(0, last_note, false).write(&mut live_buffer).unwrap();
-    output.send(&live_buffer[..]).expect("Couldn't send MIDI message!");
-    live_buffer.clear();
+fn channel_pressure(channel: u8, pressure: u8) -> LiveEvent<'static> {
+    midly::live::LiveEvent::Midi {
+        channel: channel.into(),
+        message: MidiMessage::ChannelAftertouch { vel: pressure.into() },
+    }
+}
 
-    note_swap(0, *curr_note, true).write(&mut live_buffer).unwrap();
-    output.send(&live_buffer[..]).expect("Couldn't send MIDI message!");
+fn controller(channel: u8, controller: u8, value: u8) -> LiveEvent<'static> {
+    midly::live::LiveEvent::Midi {
+        channel: channel.into(),
+        message: MidiMessage::Controller {
+            controller: controller.into(),
+            value: value.into(),
+        },
+    }
+}
+
+
+// sets a registered parameter, followed by the null RPN so later data entry is ignored
+fn send_rpn(channel: u8, parameter: u8, value: u8, output: &mut dyn MidiSink) -> Result<(), SendError> {
+    let messages = [
+        (CC_RPN_MSB, 0),
+        (CC_RPN_LSB, parameter),
+        (CC_DATA_ENTRY_MSB, value),
+        (CC_DATA_ENTRY_LSB, 0),
+        (CC_RPN_MSB, RPN_NULL),
+        (CC_RPN_LSB, RPN_NULL),
+    ];
+    for (cc, value) in messages {
+        output.send_event(controller(channel, cc, value))?;
+    }
+    Ok(())
+}
+
+fn amplitude_to_pressure(amplitude: f32) -> u8 {
+    let db = 20.0 * amplitude.max(f32::EPSILON).log10();
+    (((db - PRESSURE_FLOOR_DB) / -PRESSURE_FLOOR_DB) * 127.0).clamp(0.0, 127.0) as u8
+}
+
+// snaps a sung key into the scale, decided once per sung key so a pitch wavering around a tie can't flip the note
+fn snap_key(config: &MidiConfig, snapped_key: &mut Option<(u8, u8)>, key: u8, pitch: f32) -> u8 {
+    let snapped = match *snapped_key {
+        Some((sung, snapped)) if sung == key => snapped,
+        _ => config.quantizer.snap(key, pitch, &config.tuning),
+    };
+    *snapped_key = Some((key, snapped));
+    snapped
+}
+
+fn next_member_channel(channel: u8) -> u8 {
+    if channel + 1 >= MPE_FIRST_MEMBER + MPE_MEMBER_COUNT { MPE_FIRST_MEMBER } else { channel + 1 }
+}
+
+// ends whatever note is held, leaving the state idle
+fn release(state: &mut NoteState, style: NoteOffStyle, output: &mut dyn MidiSink) -> Result<(), SendError> {
+    if let NoteState::Sounding { channel, key } = *state {
+        output.send_event(note_off(channel, key, style))?;
+    }
+    *state = NoteState::Idle;
+    Ok(())
 }
 
 impl MidiHandlerThread {
-    pub fn new(f0_rx: BusReader<(f32, f32, bool, f32)>, running: Arc<AtomicBool>) -> MidiHandlerThread {
+    pub fn new(
+        f0_rx: BusReader<PitchFrame>,
+        config: MidiConfig,
+        reference: Arc<AtomicU32>,
+        running: Arc<AtomicBool>
+    ) -> MidiHandlerThread {
+        reference.store(config.tuning.reference().to_bits(), Ordering::SeqCst);
         MidiHandlerThread {
             freq_rx: f0_rx,
-            buffer: AllocRingBuffer::with_capacity(BUFFER_CAP.into()),
-            running: running,
+            segmenter: segment::NoteSegmenter::new(
+                config.median_frames,
+                config.hysteresis_cents,
+                config.min_note_ms,
+                config.release_ms
+            ),
+            config,
+            reference,
+            running,
         }
     }
 
-    pub fn run(&mut self) {
-        let midi_out = midir::MidiOutput::new("main").unwrap();
-        if midi_out.port_count() < 1 {
-            println!("couldn't find any midi outputs!");
-            std::process::exit(0);
+    // runs the note logic until the pitch thread stops, sending everything to output
+    pub fn run_with(&mut self, output: &mut dyn MidiSink) -> Result<(), SendError> {
+        let mut channel: u8 = self.config.channel;
+        match self.config.mode {
+            MidiMode::Mpe => {
+                // mpe configuration message, then the bend range on every member channel since it resets them to 48
+                send_rpn(MPE_MANAGER_CHANNEL, RPN_MPE_CONFIGURATION, MPE_MEMBER_COUNT, output)?;
+                for member in MPE_FIRST_MEMBER..MPE_FIRST_MEMBER + MPE_MEMBER_COUNT {
+                    send_rpn(member, RPN_PITCH_BEND_SENSITIVITY, self.config.bend_range, output)?;
+                }
+                // every note rotates onto the next member before it starts, so the first one lands on the first member
+                channel = MPE_FIRST_MEMBER + MPE_MEMBER_COUNT - 1;
+            }
+            MidiMode::Bend if self.config.bend_rpn => {
+                send_rpn(channel, RPN_PITCH_BEND_SENSITIVITY, self.config.bend_range, output)?;
+            }
+            _ => {}
         }
-        let main_port = &midi_out.ports()[midi_out.port_count() - 1]; //chooses the last midi device
-        let port_name = midi_out.port_name(&main_port).expect("couldn't find port name!");
-
-        let mut output_connection = midi_out
-            .connect(&main_port, &port_name)
-            .expect("couldn't establish connection");
 
-        let mut last_note: u8 = 0;
+        let mut state = NoteState::Idle;
+        let mut last_bend: Option<i16> = None;
+        let mut last_pressure: Option<u8> = None;
+        let mut last_brightness: Option<u8> = None;
+        let mut peak_history: AllocRingBuffer<f32> = AllocRingBuffer::with_capacity(ONSET_LEVEL_FRAMES);
+        let mut note_history: AllocRingBuffer<Vec<u8>> = AllocRingBuffer::with_capacity(POLY_VOTE_FRAMES);
+        let mut sounding: Vec<u8> = Vec::new();
+        let mut reference_estimator = ReferenceEstimator::default();
+        let mut snapped_key: Option<(u8, u8)> = None; // (sung key, key it was snapped to)
+        let mut bent_from: u8 = 0; // sung key the sounding bend note started on
 
         loop {
             if !self.running.load(Ordering::SeqCst) {
                 break;
             }
-            let (_timestamp, f0, _voiced, _vprob) = match self.freq_rx.recv() {
+            let frame = match self.freq_rx.recv() {
                 Ok(data) => data,
                 Err(_) => break
             };
 
-            self.buffer.push(f0);
+            output.set_time(frame.timestamp);
+            // the attack can land a frame or two before the pitch settles, so look back for it
+            peak_history.push(frame.peak);
+            let velocity = self.config.velocity(peak_history.iter().fold(0.0f32, |peak, &x| peak.max(x)));
+            // the monophonic modes play what the segmentation settles on, chords follow frame.notes instead
+            if self.config.auto_reference && frame.voiced {
+                if let Some(a4) = reference_estimator.observe(&self.config.tuning, frame.f0, frame.timestamp) {
+                    self.config.tuning.set_reference(a4);
+                    self.reference.store(a4.to_bits(), Ordering::SeqCst);
+                }
+            }
+            let segment = self.segmenter.process(&frame, &self.config.tuning);
+            let snapped = segment.key.map(|key| snap_key(&self.config, &mut snapped_key, key, segment.pitch));
+
+            match self.config.mode {
+                MidiMode::Note => {
+                    match snapped {
+                        None => release(&mut state, self.config.note_off, output)?,
+                        Some(key) if segment.attack || state != (NoteState::Sounding { channel, key }) => {
+                            release(&mut state, self.config.note_off, output)?;
+                            output.send_event(note_on(channel, key, velocity))?;
+                            state = NoteState::Sounding { channel, key };
+                        }
+                        Some(_) => {}
+                    }
+                }
+                MidiMode::Bend | MidiMode::Mpe => {
+                    let (Some(key), Some(snapped)) = (segment.key, snapped) else {
+                        release(&mut state, self.config.note_off, output)?;
+                        continue;
+                    };
+                    let pitch = segment.pitch;
+                    let tuning = &self.config.tuning;
+                    let bend_range = self.config.bend_range as f32;
+                    let expressive = self.config.mode == MidiMode::Mpe;
+
+                    // only retrigger on an onset or once the pitch wanders past what the bend range can reach.
+                    // a note bends from the sung key it started on, so the intonation around that key carries over to
+                    // the snapped one and bend stays in key, however the segmentation moves on meanwhile
+                    let held = match state {
+                        NoteState::Sounding { key, .. } if !segment.attack && (pitch - tuning.key_pitch(bent_from)).abs() <= bend_range => Some(key),
+                        _ => None,
+                    };
+                    let retrigger = held.is_none();
+                    // gliding out of range can get ahead of the segmentation, then the nearest key is snapped instead
+                    let note = match held {
+                        Some(held) => held,
+                        None if (pitch - tuning.key_pitch(key)).abs() <= bend_range => {
+                            bent_from = key;
+                            snapped
+                        }
+                        None => {
+                            bent_from = tuning.nearest_key(pitch);
+                            snap_key(&self.config, &mut snapped_key, bent_from, pitch)
+                        }
+                    };
+
+                    let bend = PitchBend::from_f32((pitch - tuning.key_pitch(bent_from)) / bend_range);
+                    if retrigger {
+                        release(&mut state, self.config.note_off, output)?;
+                        if expressive {
+                            // the new note gets a fresh member channel, whose expression state is unknown
+                            channel = next_member_channel(channel);
+                            last_pressure = None;
+                            last_brightness = None;
+                        }
+                    }
+                    if retrigger || last_bend != Some(bend.as_int()) {
+                        output.send_event(pitch_bend(channel, bend))?;
+                    }
+                    last_bend = Some(bend.as_int());
+
+                    if expressive {
+                        let pressure = amplitude_to_pressure(frame.amplitude);
+                        if last_pressure != Some(pressure) {
+                            output.send_event(channel_pressure(channel, pressure))?;
+                            last_pressure = Some(pressure);
+                        }
+                        let brightness = (frame.brightness * 127.0).round() as u8;
+                        if last_brightness != Some(brightness) {
+                            output.send_event(controller(channel, CC_BRIGHTNESS, brightness))?;
+                            last_brightness = Some(brightness);
+                        }
+                    }
+
+                    // bend and expression are set before the note-on so the new note starts in tune
+                    if retrigger {
+                        output.send_event(note_on(channel, note, velocity))?;
+                        state = NoteState::Sounding { channel, key: note };
+                    }
+                }
+                MidiMode::Poly => {
+                    let mut detected = frame.notes
+                        .iter()
+                        .map(|&(f0, _)| {
+                            let tuning = &self.config.tuning;
+                            self.config.quantizer.snap(tuning.note(f0), tuning.pitch(f0), tuning)
+                        })
+                        .collect::<Vec<u8>>();
+                    detected.sort_unstable();
+                    detected.dedup();
+                    note_history.push(detected);
+
+                    let mut voted = note_history.iter().flatten().copied().collect::<Vec<u8>>();
+                    voted.sort_unstable();
+                    voted.dedup();
+                    voted.retain(|note| {
+                        2 * note_history.iter().filter(|notes| notes.contains(note)).count() > note_history.len()
+                    });
+
+                    // an onset restarts the notes that carry on through it
+                    for &note in sounding.iter().filter(|note| frame.onset || !voted.contains(note)) {
+                        output.send_event(note_off(channel, note, self.config.note_off))?;
+                    }
+                    for &note in voted.iter().filter(|note| frame.onset || !sounding.contains(note)) {
+                        output.send_event(note_on(channel, note, velocity))?;
+                    }
+                    sounding = voted;
+                }
+            }
+        }
 
-            let note = get_midi_note(self.buffer.iter().sum::<f32>() / (BUFFER_CAP as f32));
-            if note != last_note {
-                send_live_message(&note, last_note, &mut output_connection);
-                last_note = note;
+        // release whatever is still held so nothing hangs once the stream ends
+        match self.config.mode {
+            MidiMode::Poly => {
+                for &note in sounding.iter() {
+                    output.send_event(note_off(channel, note, self.config.note_off))?;
+                }
             }
+            _ => release(&mut state, self.config.note_off, output)?,
         }
+        Ok(())
     }
 }
@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+
+use crate::pitchdetect::PitchFrame;
+use crate::tuning::Tuning;
+
+// what the monophonic modes should be playing after a frame
+pub struct Segment {
+    pub key: Option<u8>, // note that should be sounding, none once the input has gone quiet
+    pub pitch: f32, // median filtered midi pitch, fractional for pitch bend
+    pub attack: bool, // the note starts now, from silence or on an onset, rather than gliding from the last one
+}
+
+// turns the frame by frame pitch track into notes: a median filter over recent midi pitches,
+// hysteresis so a pitch hovering on a semitone boundary doesn't flip between keys,
+// a minimum length a new note has to hold before it sounds and a delay before silence releases it
+pub struct NoteSegmenter {
+    median_frames: usize,
+    hysteresis: f32, // semitones past the boundary with the neighbouring key
+    min_note: f64, // microseconds
+    release: f64, // microseconds
+    pitches: VecDeque<f32>,
+    pitch: f32,
+    key: Option<u8>,
+    candidate: Option<(u8, f64)>, // key waiting to last min_note, with the time it was first seen
+    unvoiced_since: Option<f64>,
+    pending_attack: bool,
+}
+
+impl NoteSegmenter {
+    pub fn new(median_frames: usize, hysteresis_cents: f32, min_note_ms: f64, release_ms: f64) -> NoteSegmenter {
+        NoteSegmenter {
+            median_frames: median_frames.max(1),
+            hysteresis: hysteresis_cents.clamp(0.0, 50.0) / 100.0,
+            min_note: min_note_ms * 1e3,
+            release: release_ms * 1e3,
+            pitches: VecDeque::with_capacity(median_frames.max(1)),
+            pitch: 0.0,
+            key: None,
+            candidate: None,
+            unvoiced_since: None,
+            pending_attack: false,
+        }
+    }
+
+    // the tuning is passed in each frame since its reference can move while the stream plays
+    pub fn process(&mut self, frame: &PitchFrame, tuning: &Tuning) -> Segment {
+        let mut attack = false;
+        if frame.voiced {
+            self.unvoiced_since = None;
+            // an onset starts a new note, so the filter shouldn't carry the last one into it
+            if frame.onset || self.key.is_none() {
+                if frame.onset {
+                    self.pitches.clear();
+                }
+                self.pending_attack = true;
+            }
+            if self.pitches.len() == self.median_frames {
+                self.pitches.pop_front();
+            }
+            self.pitches.push_back(tuning.pitch(frame.f0));
+            self.pitch = median(&self.pitches);
+
+            // the boundary between two keys lies halfway between the pitches they have in the tuning
+            let nearest = tuning.nearest_key(self.pitch);
+            let target = match self.key {
+                Some(key) if key != nearest => {
+                    let past_boundary =
+                        ((self.pitch - tuning.key_pitch(key)).abs() - (self.pitch - tuning.key_pitch(nearest)).abs()) / 2.0;
+                    if past_boundary <= self.hysteresis { key } else { nearest }
+                }
+                _ => nearest,
+            };
+            if self.key == Some(target) {
+                self.candidate = None;
+                attack = std::mem::take(&mut self.pending_attack);
+            } else {
+                let since = match self.candidate {
+                    Some((key, since)) if key == target => since,
+                    _ => frame.timestamp,
+                };
+                if frame.timestamp - since >= self.min_note {
+                    self.key = Some(target);
+                    self.candidate = None;
+                    attack = std::mem::take(&mut self.pending_attack);
+                } else {
+                    self.candidate = Some((target, since));
+                }
+            }
+        } else {
+            // a new note has to be heard without a break to count
+            self.candidate = None;
+            let since = *self.unvoiced_since.get_or_insert(frame.timestamp);
+            if self.key.is_some() && frame.timestamp - since >= self.release {
+                self.key = None;
+                self.pitches.clear();
+            }
+            if self.key.is_none() {
+                self.pending_attack = false;
+            }
+        }
+        Segment { key: self.key, pitch: self.pitch, attack }
+    }
+}
+
+fn median(values: &VecDeque<f32>) -> f32 {
+    let mut sorted = values.iter().copied().collect::<Vec<f32>>();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 { 0.5 * (sorted[mid - 1] + sorted[mid]) } else { sorted[mid] }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuning::TuningSystem;
+
+    fn equal() -> Tuning {
+        Tuning::new(440.0, TuningSystem::Equal, 0, None, None).unwrap()
+    }
+
+    fn voiced(ms: f64, pitch: f32) -> PitchFrame {
+        PitchFrame {
+            timestamp: ms * 1e3,
+            f0: 440.0 * (2.0f32).powf((pitch - 69.0) / 12.0),
+            voiced: true,
+            ..Default::default()
+        }
+    }
+
+    fn unvoiced(ms: f64) -> PitchFrame {
+        PitchFrame { timestamp: ms * 1e3, ..Default::default() }
+    }
+
+    // feeds one frame every 10ms from start_ms, returning the key and attack after each
+    fn feed(segmenter: &mut NoteSegmenter, tuning: &Tuning, start_ms: f64, frames: &[Option<f32>]) -> Vec<(Option<u8>, bool)> {
+        frames
+            .iter()
+            .enumerate()
+            .map(|(i, pitch)| {
+                let ms = start_ms + 10.0 * (i as f64);
+                let frame = match pitch {
+                    Some(pitch) => voiced(ms, *pitch),
+                    None => unvoiced(ms),
+                };
+                let segment = segmenter.process(&frame, tuning);
+                (segment.key, segment.attack)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn note_sounds_once_it_has_held_for_min_length() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(1, 30.0, 40.0, 50.0);
+        let out = feed(&mut segmenter, &tuning, 0.0, &[Some(69.0); 6]);
+        assert_eq!(out, vec![(None, false), (None, false), (None, false), (None, false), (Some(69), true), (Some(69), false)]);
+    }
+
+    #[test]
+    fn blips_shorter_than_min_length_are_dropped() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(1, 30.0, 40.0, 50.0);
+        feed(&mut segmenter, &tuning, 0.0, &[Some(69.0); 5]);
+        let out = feed(&mut segmenter, &tuning, 50.0, &[Some(72.0), Some(72.0), Some(72.0), Some(69.0), Some(69.0)]);
+        assert!(out.iter().all(|&segment| segment == (Some(69), false)));
+    }
+
+    #[test]
+    fn median_filter_ignores_single_frame_outliers() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(3, 30.0, 0.0, 50.0);
+        feed(&mut segmenter, &tuning, 0.0, &[Some(69.0); 3]);
+        let out = feed(&mut segmenter, &tuning, 30.0, &[Some(81.0), Some(69.0), Some(69.0)]);
+        assert!(out.iter().all(|&segment| segment == (Some(69), false)));
+    }
+
+    #[test]
+    fn hysteresis_holds_the_key_until_the_pitch_is_well_past_the_boundary() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(1, 30.0, 0.0, 50.0);
+        assert_eq!(feed(&mut segmenter, &tuning, 0.0, &[Some(69.0)]), vec![(Some(69), true)]);
+        // 10 and 25 cents past the boundary with 70
+        let out = feed(&mut segmenter, &tuning, 10.0, &[Some(69.6), Some(69.75)]);
+        assert_eq!(out, vec![(Some(69), false), (Some(69), false)]);
+        // 35 cents past it moves on, gliding rather than attacking
+        assert_eq!(feed(&mut segmenter, &tuning, 30.0, &[Some(69.85)]), vec![(Some(70), false)]);
+        // and coming back needs the same margin the other way
+        assert_eq!(feed(&mut segmenter, &tuning, 40.0, &[Some(69.4)]), vec![(Some(70), false)]);
+        assert_eq!(feed(&mut segmenter, &tuning, 50.0, &[Some(69.1)]), vec![(Some(69), false)]);
+    }
+
+    #[test]
+    fn silence_releases_the_note_after_the_release_delay() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(1, 30.0, 0.0, 50.0);
+        feed(&mut segmenter, &tuning, 0.0, &[Some(69.0)]);
+        let out = feed(&mut segmenter, &tuning, 10.0, &[None; 7]);
+        let keys = out.iter().map(|&(key, _)| key).collect::<Vec<Option<u8>>>();
+        assert_eq!(keys, vec![Some(69), Some(69), Some(69), Some(69), Some(69), None, None]);
+        // the next note is a fresh attack
+        assert_eq!(feed(&mut segmenter, &tuning, 80.0, &[Some(69.0)]), vec![(Some(69), true)]);
+    }
+
+    #[test]
+    fn dropouts_shorter_than_the_release_delay_are_bridged() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(1, 30.0, 0.0, 50.0);
+        feed(&mut segmenter, &tuning, 0.0, &[Some(69.0)]);
+        let out = feed(&mut segmenter, &tuning, 10.0, &[None, None, None, Some(69.0), Some(69.0)]);
+        assert!(out.iter().all(|&segment| segment == (Some(69), false)));
+    }
+
+    #[test]
+    fn onsets_retrigger_a_held_note() {
+        let tuning = equal();
+        let mut segmenter = NoteSegmenter::new(1, 30.0, 0.0, 50.0);
+        feed(&mut segmenter, &tuning, 0.0, &[Some(69.0), Some(69.0)]);
+        let mut frame = voiced(20.0, 69.0);
+        frame.onset = true;
+        let segment = segmenter.process(&frame, &tuning);
+        assert_eq!((segment.key, segment.attack), (Some(69), true));
+    }
+}
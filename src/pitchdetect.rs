@@ -1,50 +1,179 @@
 use bus::{ Bus, BusReader };
-use ringbuffer::{ AllocRingBuffer, RingBufferWrite };
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::atomic::{ AtomicBool, Ordering };
 
 use crate::MIN_FREQ;
-use crate::NUM_FREQS;
-use crate::SNAPSHOT_BUFFLEN;
+use crate::MAX_FREQ;
 mod goertzel;
+mod hmm;
+mod mpm;
+mod onset;
+mod poly;
+mod yin;
 
-const NUM_FRAMES_CONCAT: usize = 32;
+// fits a parabola through d[idx - 1..=idx + 1], returning (fractional index, value) of its vertex
+fn parabolic_peak(d: &[f32], idx: usize) -> (f32, f32) {
+    if idx < 1 || idx + 1 >= d.len() {
+        return (idx as f32, d[idx]);
+    }
+    let (s0, s1, s2) = (d[idx - 1], d[idx], d[idx + 1]);
+    let denom = s0 - 2.0 * s1 + s2;
+    if denom.abs() < f32::EPSILON {
+        return (idx as f32, s1);
+    }
+    let delta = (0.5 * (s0 - s2)) / denom;
+    (idx as f32 + delta, s1 - 0.25 * (s0 - s2) * delta)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PitchFrame {
+    pub timestamp: f64, // microseconds
+    pub f0: f32, // hz
+    pub voiced: bool,
+    pub confidence: f32, // 0-1 estimator confidence the voicing decision was made on
+    pub amplitude: f32, // rms of the latest snapshot
+    pub peak: f32, // largest absolute sample of the latest snapshot
+    pub onset: bool, // a new note was attacked in the latest snapshot, even at the same pitch
+    pub brightness: f32, // 0 for a pure tone at MIN_FREQ up to 1 for content centred at MAX_FREQ
+    pub notes: Vec<(f32, f32)>, // simultaneous (f0 in hz, confidence) pairs, empty when unvoiced
+}
+
+// rms level, plus a log-frequency brightness from the rms of the first difference,
+// which for any signal equals 2 sin(pi fc / sr) times its rms, fc being the power-weighted mean frequency
+fn level_and_brightness(buff: &[f32], sr: f32) -> (f32, f32) {
+    let len = buff.len().max(1) as f32;
+    let rms = (buff.iter().map(|x| x * x).sum::<f32>() / len).sqrt();
+    if rms <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let diff_rms = (buff.windows(2).map(|w| (w[1] - w[0]) * (w[1] - w[0])).sum::<f32>() / len).sqrt();
+    let centroid = (sr / std::f32::consts::PI) * (diff_rms / (2.0 * rms)).min(1.0).asin();
+    let brightness = (centroid.max(MIN_FREQ) / MIN_FREQ).log2() / (MAX_FREQ / MIN_FREQ).log2();
+    (rms, brightness.clamp(0.0, 1.0))
+}
+
+// thresholds the pitch thread makes its per-frame decisions with
+pub struct DetectionConfig {
+    pub clarity_thresh: f32, // estimator confidence above which a frame is voiced
+    pub onset_thresh: f32, // db a snapshot's level has to rise above the recent average to count as an onset
+}
+
+pub trait PitchEstimator: Send {
+    // analyse the latest concatenated audio frame
+    fn process(&mut self, buff: &[f32]);
+
+    // returns (f0 in hz, confidence from 0 to 1) for the last processed frame
+    fn get_pitch(&mut self) -> (f32, f32);
+
+    // all simultaneous (f0 in hz, confidence) pairs, monophonic estimators report at most one
+    fn get_pitches(&mut self) -> Vec<(f32, f32)> {
+        match self.get_pitch() {
+            (f0, confidence) if f0 > 0.0 => vec![(f0, confidence)],
+            _ => Vec::new(),
+        }
+    }
+
+    // number of most recent samples the estimator wants to see
+    fn window_len(&self) -> usize;
+
+    // frames the reported pitch trails the latest processed frame by
+    fn delay(&self) -> usize {
+        0
+    }
+
+    // per-bin magnitudes for the spectrogram, if the estimator computes one
+    fn spectrum(&self) -> Option<&[f32]> {
+        None
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum EstimatorKind {
+    Goertzel,
+    Yin,
+    Pyin,
+    Mpm,
+    Poly,
+}
+
+impl EstimatorKind {
+    // window overrides the estimator's default analysis length, hmm_lag only applies to goertzel
+    pub fn build(&self, sr: f32, window: Option<usize>, bins_per_semitone: usize, hmm_lag: Option<usize>) -> Box<dyn PitchEstimator> {
+        match self {
+            EstimatorKind::Goertzel =>
+                Box::new(
+                    goertzel::GoertzelEstimator::new(
+                        MIN_FREQ,
+                        sr,
+                        bins_per_semitone,
+                        window.unwrap_or(goertzel::GOERTZEL_WINDOW),
+                        hmm_lag
+                    )
+                ),
+            EstimatorKind::Yin =>
+                Box::new(yin::YinEstimator::new(MIN_FREQ, MAX_FREQ, sr, window.unwrap_or(yin::YIN_WINDOW), false)),
+            EstimatorKind::Pyin =>
+                Box::new(yin::YinEstimator::new(MIN_FREQ, MAX_FREQ, sr, window.unwrap_or(yin::YIN_WINDOW), true)),
+            EstimatorKind::Mpm =>
+                Box::new(mpm::MpmEstimator::new(MIN_FREQ, MAX_FREQ, sr, window.unwrap_or(mpm::MPM_WINDOW))),
+            EstimatorKind::Poly =>
+                Box::new(poly::PolyEstimator::new(MIN_FREQ, sr, bins_per_semitone, window.unwrap_or(goertzel::GOERTZEL_WINDOW))),
+        }
+    }
+
+    // samples the goertzel bank filters per frame, none for estimators that don't run one
+    pub fn filter_samples(&self, sr: f32, window: Option<usize>, bins_per_semitone: usize) -> Option<usize> {
+        match self {
+            EstimatorKind::Goertzel | EstimatorKind::Poly =>
+                Some(goertzel::filter_samples(MIN_FREQ, sr, bins_per_semitone, window.unwrap_or(goertzel::GOERTZEL_WINDOW))),
+            _ => None,
+        }
+    }
+}
 
 pub struct PitchEstimatorThread {
-    audio_rx: BusReader<[(f32, f32); SNAPSHOT_BUFFLEN]>,
-    pitch_tx: Bus<(f32, f32, bool, f32)>, //sends (frequency float in hz, voiced bool, voiced probability float)
-    spec_tx: Bus<[f32; NUM_FREQS]>,
-    waveform_snapshot_buffer: AllocRingBuffer<[(f32, f32); SNAPSHOT_BUFFLEN]>,
-    predictor: goertzel::GoertzelEstimator,
+    audio_rx: BusReader<Vec<(f64, f32)>>,
+    pitch_tx: Bus<PitchFrame>,
+    spec_tx: Bus<Vec<f32>>,
+    history: VecDeque<(f64, f32)>, // the last window_len samples, however many hops that spans
+    filled: usize, // samples of history that came from the stream rather than the initial padding
+    pending: VecDeque<(PitchFrame, Vec<f32>)>, // frames whose pitch the estimator hasn't reported yet, with their spectra
+    window_len: usize,
+    predictor: Box<dyn PitchEstimator>,
+    onsets: onset::OnsetDetector,
+    sr: f32,
     cthresh: f32,
     running: Arc<AtomicBool>,
 }
 
 impl PitchEstimatorThread {
     pub fn new(
-        sr: usize,
-        snapshot_ref: BusReader<[(f32, f32); SNAPSHOT_BUFFLEN]>,
-        f0_tx: Bus<(f32, f32, bool, f32)>,
-        spec_tx: Bus<[f32; NUM_FREQS]>,
-        cthresh: f32,
+        predictor: Box<dyn PitchEstimator>,
+        sr: f32,
+        snapshot_ref: BusReader<Vec<(f64, f32)>>,
+        f0_tx: Bus<PitchFrame>,
+        spec_tx: Bus<Vec<f32>>,
+        config: DetectionConfig,
         running: Arc<AtomicBool>,
     ) -> PitchEstimatorThread {
-        let detector = goertzel::GoertzelEstimator::new(MIN_FREQ, sr as f32);
-        let mut ringbuff = AllocRingBuffer::with_capacity(NUM_FRAMES_CONCAT);
-        ringbuff.push([(0.0, 0.0); SNAPSHOT_BUFFLEN]);
+        let window_len = predictor.window_len().max(1);
         PitchEstimatorThread {
             audio_rx: snapshot_ref,
             pitch_tx: f0_tx,
-            spec_tx: spec_tx,
-            waveform_snapshot_buffer: ringbuff,
-            predictor: detector,
-            cthresh: cthresh,
-            running: running,
+            spec_tx,
+            history: std::iter::repeat_n((0.0, 0.0), window_len).collect(),
+            filled: 0,
+            window_len,
+            pending: VecDeque::new(),
+            predictor,
+            onsets: onset::OnsetDetector::new(config.onset_thresh),
+            sr,
+            cthresh: config.clarity_thresh,
+            running,
         }
     }
     pub fn run(&mut self) {
-        let mut multi_frame_snapshot = [(0.0, 0.0); SNAPSHOT_BUFFLEN * NUM_FRAMES_CONCAT];
-
         loop {
             if !self.running.load(Ordering::SeqCst) {
                 break;
@@ -54,26 +183,61 @@ impl PitchEstimatorThread {
                 Ok(data) => data,
                 Err(_) => break,
             };
-            self.waveform_snapshot_buffer.push(snapshot);
-
-            for i in 0..NUM_FRAMES_CONCAT {
-                for j in 0..SNAPSHOT_BUFFLEN {
-                    multi_frame_snapshot[i * SNAPSHOT_BUFFLEN + j] = self.waveform_snapshot_buffer[
-                        i as isize
-                    ][j];
-                }
-            }
+            let hop = snapshot.len();
+            let hop_start = snapshot.first().map(|&(time, _)| time).unwrap_or_default();
+            self.history.extend(snapshot);
+            let excess = self.history.len().saturating_sub(self.window_len);
+            self.history.drain(..excess);
 
-            let timestamp = multi_frame_snapshot[0].0;
-            let amps = multi_frame_snapshot
+            // a window still partly made of padding would date its estimate before the stream started
+            self.filled = (self.filled + hop).min(self.window_len);
+            if self.filled < self.window_len {
+                continue;
+            }
+            let amps = self.history
                 .iter()
                 .map(|el| el.1)
                 .collect::<Vec<f32>>();
             self.predictor.process(amps.as_slice());
-            let pitch = self.predictor.get_pitch();
 
-            self.spec_tx.broadcast(self.predictor.gvec);
-            self.pitch_tx.broadcast((timestamp, pitch.0, pitch.1 > self.cthresh, pitch.1));
+            // estimators without a spectrum send an empty one, leaving the spectrogram blank
+            let spectrum = self.predictor
+                .spectrum()
+                .map(|bins| bins.to_vec())
+                .unwrap_or_default();
+
+            let latest = &amps[amps.len().saturating_sub(hop)..];
+            let (amplitude, brightness) = level_and_brightness(latest, self.sr);
+            let peak = latest.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            let onset = self.onsets.process(amplitude, hop_start);
+            // stamped with the middle of the window, the instant its estimate describes best
+            self.pending.push_back((
+                PitchFrame {
+                    timestamp: self.history[self.window_len / 2].0,
+                    amplitude,
+                    peak,
+                    onset,
+                    brightness,
+                    ..Default::default()
+                },
+                spectrum,
+            ));
+
+            // a smoothing estimator reports on an older frame, which is matched with that frame's timing, level
+            // and spectrum, so the spectrogram never runs ahead of the pitch track
+            if self.pending.len() <= self.predictor.delay() {
+                continue;
+            }
+            let Some((mut frame, spectrum)) = self.pending.pop_front() else {
+                continue;
+            };
+            let pitch = self.predictor.get_pitch();
+            frame.f0 = pitch.0;
+            frame.voiced = pitch.1 > self.cthresh;
+            frame.confidence = pitch.1;
+            frame.notes = if frame.voiced { self.predictor.get_pitches() } else { Vec::new() };
+            self.spec_tx.broadcast(spectrum);
+            self.pitch_tx.broadcast(frame);
         }
     }
 }
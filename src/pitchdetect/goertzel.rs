@@ -1,106 +1,240 @@
-use std::array;
-
+use super::{ hmm::PitchHmm, parabolic_peak, PitchEstimator };
 use crate::NOISE_THRESH;
 use crate::NUM_FREQS;
+pub const GOERTZEL_WINDOW: usize = 32768;
 const F0_THRESH_COEFF: f32 = 0.05;
 //TODO: tune thresh
 
-fn argmax(slice: &[f32]) -> i8 {
+// semitone offsets of the subharmonics checked for octave errors, lowest first
+const SUBHARMONIC_OFFSETS: [usize; 4] = [24, 19, 12, 7];
+
+fn argmax(slice: &[f32]) -> Option<usize> {
     let mut max = f32::NEG_INFINITY;
-    let mut max_idx: i8 = -1;
-    for i in 0..slice.len() {
-        if slice[i] > max {
-            max_idx = i as i8;
-            max = slice[i];
+    let mut max_idx = None;
+    for (i, &val) in slice.iter().enumerate() {
+        if val > max {
+            max_idx = Some(i);
+            max = val;
         }
     }
-    return max_idx;
+    max_idx
 }
-fn argmin(slice: &[f32]) -> i8 {
-    let mut min = f32::INFINITY;
-    let mut min_idx: i8 = -1;
-    for i in 1..slice.len() {
-        if slice[i] < min {
-            min_idx = i as i8;
-            min = slice[i];
-        }
-    }
-    return min_idx;
+
+// a filter magnitude as a 0-1 confidence, one half right at the noise threshold
+pub fn magnitude_confidence(magnitude: f32) -> f32 {
+    let power = magnitude * magnitude;
+    power / (power + NOISE_THRESH * NOISE_THRESH)
 }
 
 // TODO: execute goertzel for 4 freqs at once via SIMD
 pub fn goertzel(buff: &[f32], target_freq: f32, srate: f32) -> f32 {
-    let k = (0.5 + ((buff.len() as f32 * target_freq) / srate)).floor();
-    let w = (2.0 * std::f32::consts::PI / (buff.len() as f32)) * k;
+    // exact target frequency rather than the nearest dft bin, so closely spaced filters stay distinct
+    let w = (2.0 * std::f32::consts::PI * target_freq) / srate;
 
     let coeff = 2.0 * w.cos();
 
+    // the samples are hann tapered, the window's cosine coming from a phasor turned once per sample
+    let step = (2.0 * std::f32::consts::PI) / (buff.len().max(1) as f32);
+    let (step_cos, step_sin) = (step.cos(), step.sin());
+    let (mut cos, mut sin) = (1.0f32, 0.0f32);
+
     let mut q0;
     let mut q1 = 0.0;
     let mut q2 = 0.0;
-    for i in 1..buff.len() {
-        q0 = coeff * q1 - q2 + buff[i];
+    for &sample in buff.iter() {
+        q0 = coeff * q1 - q2 + sample * 0.5 * (1.0 - cos);
         q2 = q1;
         q1 = q0;
+        (cos, sin) = (cos * step_cos - sin * step_sin, sin * step_cos + cos * step_sin);
     }
 
     let magsquared = q1 * q1 + (q2 * q2) - (q1 * q2 * coeff);
-    return magsquared.sqrt();
+    magsquared.sqrt()
+}
+
+// samples each filter looks at, constant q so that a hann main lobe reaches two bins either side of its
+// centre, letting every filter overlap its neighbours and the peak be interpolated between them
+fn filter_lengths(freqs: &[f32], bin_ratio: f32, srate: f32, window: usize) -> Vec<usize> {
+    let q = 1.0 / (bin_ratio - 1.0);
+    freqs
+        .iter()
+        .map(|freq| (((q * srate) / freq).round() as usize).clamp(1, window.max(1)))
+        .collect()
+}
+
+fn bin_freqs(min_freq: f32, bins_per_semitone: usize) -> (Vec<f32>, f32) {
+    let bin_ratio: f32 = 2.0f32.powf(1.0 / ((12 * bins_per_semitone) as f32));
+    let freqs = (0..NUM_FREQS * bins_per_semitone)
+        .map(|i| min_freq * bin_ratio.powf(i as f32))
+        .collect();
+    (freqs, bin_ratio)
+}
+
+// samples the whole bank runs through per frame, which is what its cost grows with
+pub fn filter_samples(min_freq: f32, srate: f32, bins_per_semitone: usize, window: usize) -> usize {
+    let (freqs, bin_ratio) = bin_freqs(min_freq, bins_per_semitone.max(1));
+    filter_lengths(&freqs, bin_ratio, srate, window).iter().sum()
 }
 
 pub struct GoertzelEstimator {
     thresh: f32,
-    target_freqs: [f32; NUM_FREQS],
-    pub gvec: [f32; NUM_FREQS],
+    min_freq: f32,
+    bins_per_semitone: usize,
+    target_freqs: Vec<f32>,
+    lengths: Vec<usize>, // samples each filter looks at
+    gvec: Vec<f32>,
     srate: f32,
+    window: usize,
+    hmm: Option<PitchHmm>,
+    hmm_lag: usize,
+    smoothed: (f32, f32), // (f0 in hz, confidence) the hmm decided on for the frame hmm_lag frames back
 }
 
 impl GoertzelEstimator {
-    pub fn new(min_freq: f32, srate: f32) -> GoertzelEstimator {
-        let tw_root_of_two: f32 = 2.0f32.powf(1.0 / 12.0);
-
-        let freq_array: [f32; NUM_FREQS] =
-            array::from_fn(|i| min_freq * tw_root_of_two.powf(i as f32));
+    // hmm_lag turns on viterbi smoothing of the pitch track, decided that many frames behind the input
+    pub fn new(min_freq: f32, srate: f32, bins_per_semitone: usize, window: usize, hmm_lag: Option<usize>) -> GoertzelEstimator {
+        let bins_per_semitone = bins_per_semitone.max(1);
+        let num_bins = NUM_FREQS * bins_per_semitone;
+        let (freq_array, bin_ratio) = bin_freqs(min_freq, bins_per_semitone);
+        let lengths = filter_lengths(&freq_array, bin_ratio, srate, window);
 
         GoertzelEstimator {
             target_freqs: freq_array,
+            lengths,
             thresh: NOISE_THRESH,
-            gvec: [0.0; NUM_FREQS],
-            srate: srate,
+            min_freq,
+            bins_per_semitone,
+            gvec: vec![0.0; num_bins],
+            srate,
+            window,
+            hmm_lag: hmm_lag.unwrap_or(0),
+            hmm: hmm_lag.map(|lag| PitchHmm::new(num_bins, bins_per_semitone, lag)),
+            smoothed: (0.0, 0.0),
         }
     }
 
-    pub fn process(&mut self, buff: &[f32]) {
-        self.gvec = array::from_fn(|i| goertzel(buff, self.target_freqs[i], self.srate));
+    fn interpolated_freq(&self, idx: usize) -> f32 {
+        interpolated_freq(&self.gvec, idx, self.min_freq, self.bins_per_semitone)
     }
+}
 
-    pub fn get_pitch(&mut self) -> (f32, f32) {
-        let amax = argmax(&self.gvec);
-        if amax == -1 {
-            return (0.0, 0.0);
+// frequency at a fractional bin index, found by fitting a parabola to the log magnitudes around idx
+fn interpolated_freq(gvec: &[f32], idx: usize, min_freq: f32, bins_per_semitone: usize) -> f32 {
+    let bins_per_octave = (12 * bins_per_semitone) as f32;
+    if idx == 0 || idx + 1 >= gvec.len() {
+        return min_freq * (2.0f32).powf((idx as f32) / bins_per_octave);
+    }
+    let log_mags = [
+        gvec[idx - 1].max(f32::EPSILON).ln(),
+        gvec[idx].max(f32::EPSILON).ln(),
+        gvec[idx + 1].max(f32::EPSILON).ln(),
+    ];
+    let offset = (parabolic_peak(&log_mags, 1).0 - 1.0).clamp(-0.5, 0.5);
+    min_freq * (2.0f32).powf((idx as f32 + offset) / bins_per_octave)
+}
+
+impl PitchEstimator for GoertzelEstimator {
+    fn process(&mut self, buff: &[f32]) {
+        // each filter looks at the middle of the buffer, low ones being cut short by its length.
+        // magnitudes grow with the window, so scale them back to the untapered length the thresholds were tuned on
+        for ((mag, &freq), &length) in self.gvec.iter_mut().zip(&self.target_freqs).zip(&self.lengths) {
+            let length = length.min(buff.len()).max(1);
+            let start = (buff.len() - length) / 2;
+            let scale = (2.0 * (GOERTZEL_WINDOW as f32)) / (length as f32);
+            *mag = goertzel(&buff[start..start + length], freq, self.srate) * scale;
+        }
+        if let Some(hmm) = &mut self.hmm {
+            self.smoothed = match hmm.decode(&self.gvec) {
+                Some((gvec, Some(idx))) => {
+                    let peak = gvec.iter().fold(0.0f32, |peak, &mag| peak.max(mag));
+                    (interpolated_freq(&gvec, idx, self.min_freq, self.bins_per_semitone), magnitude_confidence(peak))
+                }
+                _ => (0.0, 0.0),
+            };
+        }
+    }
+
+    fn get_pitch(&mut self) -> (f32, f32) {
+        if self.hmm.is_some() {
+            return self.smoothed;
         }
-        if self.gvec[amax as usize] < self.thresh {
+        let amax = match argmax(&self.gvec) {
+            Some(idx) => idx,
+            None => {
+                return (0.0, 0.0);
+            }
+        };
+        if self.gvec[amax] < self.thresh {
             return (0.0, 0.0);
         }
 
-        let total_energy:f32 = self.gvec.iter().sum();
+        // the strongest filter decides how sure we are that anything is sounding at all
+        let confidence = magnitude_confidence(self.gvec[amax]);
+        let total_energy: f32 = self.gvec.iter().sum::<f32>() / (self.bins_per_semitone as f32);
 
         // compensate for octave error, check if our argmax is actually a harmonic of a fundemental
-        let subharmonic_candidates = vec![
-            ((amax - 24).max(0) as usize, self.gvec[(amax - 24).max(0) as usize]),
-            ((amax - 19).max(0) as usize, self.gvec[(amax - 19).max(0) as usize]),
-            ((amax - 12).max(0) as usize, self.gvec[(amax - 12).max(0) as usize]),
-            ((amax - 7).max(0) as usize, self.gvec[(amax - 7).max(0) as usize]),
-            (amax as usize, self.gvec[amax as usize])
-        ];
-
-        for subharm in subharmonic_candidates{
-            if subharm.1 > F0_THRESH_COEFF * total_energy{ 
-                return (self.target_freqs[subharm.0], self.gvec[subharm.0]);
+        let subharmonic_candidates = SUBHARMONIC_OFFSETS.iter()
+            .map(|semitones| amax.saturating_sub(semitones * self.bins_per_semitone))
+            .chain([amax]);
+
+        for subharm in subharmonic_candidates {
+            if self.gvec[subharm] > F0_THRESH_COEFF * total_energy {
+                return (self.interpolated_freq(subharm), confidence);
             }
         }
 
-        return (self.target_freqs[amax as usize], self.gvec[amax as usize]);
+        (self.interpolated_freq(amax), confidence)
+    }
+
+    fn window_len(&self) -> usize {
+        self.window
+    }
+
+    fn delay(&self) -> usize {
+        self.hmm_lag
+    }
+
+    fn spectrum(&self) -> Option<&[f32]> {
+        Some(&self.gvec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MIN_FREQ;
+
+    const SRATE: f32 = 48000.0;
+
+    fn sine(freq: f32) -> Vec<f32> {
+        (0..GOERTZEL_WINDOW)
+            .map(|i| 0.5 * ((2.0 * std::f32::consts::PI * freq * (i as f32)) / SRATE).sin())
+            .collect()
+    }
+
+    // worst error in cents over tones a few cents apart, from A1 up to the top of the bank
+    fn sweep_error(bins_per_semitone: usize) -> f32 {
+        let mut estimator = GoertzelEstimator::new(MIN_FREQ, SRATE, bins_per_semitone, GOERTZEL_WINDOW, None);
+        (0..=(12 * 5 * 4))
+            .map(|step| 55.0 * (2.0f32).powf((step as f32) / 48.0 + 0.013))
+            .map(|freq| {
+                estimator.process(&sine(freq));
+                let (f0, confidence) = estimator.get_pitch();
+                assert!(f0 > 0.0 && confidence > 0.5, "{} Hz wasn't detected", freq);
+                (1200.0 * (f0 / freq).log2()).abs()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn sweep_is_accurate_at_one_bin_per_semitone() {
+        let error = sweep_error(1);
+        assert!(error < 6.0, "off by {} cents", error);
+    }
 
+    #[test]
+    fn sweep_is_accurate_at_three_bins_per_semitone() {
+        let error = sweep_error(3);
+        assert!(error < 2.0, "off by {} cents", error);
     }
 }
@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+
+use super::goertzel::magnitude_confidence;
+
+const HMM_NUM_HARMONICS: usize = 4; // partials summed into each bin's salience
+const HMM_VOICING_SWITCH: f32 = 0.02; // chance per frame of moving between voiced and unvoiced
+const HMM_LEAP_PENALTY: f32 = 1.0; // log-probability lost per semitone a voiced pitch moves between frames
+
+// fixed-lag viterbi decoding over the goertzel bank: one state per bin plus an unvoiced state.
+// leaps cost in proportion to their size, so octave jumps and single frame outliers need
+// lasting evidence, and each decision waits lag frames for that evidence to arrive
+pub struct PitchHmm {
+    lag: usize,
+    bins_per_semitone: usize,
+    harmonic_offsets: Vec<usize>,
+    log_norm: Vec<f32>, // log of each voiced state's summed transition weights to the other voiced states
+    delta: Vec<f32>, // best path log-probability ending in each state, the unvoiced state last
+    backpointers: VecDeque<Vec<usize>>, // per frame, the previous state on the best path into each state
+    spectra: VecDeque<Vec<f32>>, // magnitudes of the frames not decided yet
+}
+
+impl PitchHmm {
+    pub fn new(num_bins: usize, bins_per_semitone: usize, lag: usize) -> PitchHmm {
+        let bins_per_octave = (12 * bins_per_semitone) as f32;
+        let step = HMM_LEAP_PENALTY / (bins_per_semitone as f32);
+        PitchHmm {
+            lag,
+            bins_per_semitone,
+            harmonic_offsets: (1..=HMM_NUM_HARMONICS)
+                .map(|h| (bins_per_octave * (h as f32).log2()).round() as usize)
+                .collect(),
+            log_norm: (0..num_bins)
+                .map(|i| {
+                    (0..num_bins)
+                        .map(|j| (-step * (i.abs_diff(j) as f32)).exp())
+                        .sum::<f32>()
+                        .ln()
+                })
+                .collect(),
+            delta: vec![0.0; num_bins + 1],
+            backpointers: VecDeque::with_capacity(lag + 1),
+            spectra: VecDeque::with_capacity(lag + 1),
+        }
+    }
+
+    // log emission probabilities: a voicing probability from the strongest bin, shared out over
+    // the bins by harmonic salience so a strong overtone doesn't outweigh its fundamental
+    fn emissions(&self, gvec: &[f32]) -> (Vec<f32>, f32) {
+        let num_bins = gvec.len();
+        let peak = gvec.iter().fold(0.0f32, |peak, &mag| peak.max(mag));
+        let voicing = magnitude_confidence(peak);
+
+        let salience = (0..num_bins)
+            .map(|i| {
+                self.harmonic_offsets
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(h, offset)| gvec.get(i + offset).map(|mag| mag / ((h + 1) as f32)))
+                    .sum::<f32>()
+                    .powi(2)
+            })
+            .collect::<Vec<f32>>();
+        let total = salience.iter().sum::<f32>().max(f32::EPSILON);
+        let voiced = salience
+            .iter()
+            .map(|s| (voicing * (s / total)).max(f32::EPSILON).ln())
+            .collect();
+        let unvoiced = ((1.0 - voicing) / (num_bins as f32)).max(f32::EPSILON).ln();
+        (voiced, unvoiced)
+    }
+
+    // adds a frame, returning its magnitudes and decided bin (none when unvoiced) for the frame lag
+    // frames back, once there is one
+    pub fn decode(&mut self, gvec: &[f32]) -> Option<(Vec<f32>, Option<usize>)> {
+        let num_bins = self.delta.len() - 1;
+        let unvoiced = num_bins;
+        let (emit_voiced, emit_unvoiced) = self.emissions(gvec);
+        let stay = (1.0 - HMM_VOICING_SWITCH).ln();
+        let switch = HMM_VOICING_SWITCH.ln();
+        let step = HMM_LEAP_PENALTY / (self.bins_per_semitone as f32);
+
+        // the best voiced predecessor of every bin, cost growing linearly with distance,
+        // found with a forward and a backward pass instead of comparing every pair
+        let mut best = (0..num_bins).map(|i| (self.delta[i] - self.log_norm[i] + stay, i)).collect::<Vec<(f32, usize)>>();
+        for j in 1..num_bins {
+            if best[j - 1].0 - step > best[j].0 {
+                best[j] = (best[j - 1].0 - step, best[j - 1].1);
+            }
+        }
+        for j in (0..num_bins.saturating_sub(1)).rev() {
+            if best[j + 1].0 - step > best[j].0 {
+                best[j] = (best[j + 1].0 - step, best[j + 1].1);
+            }
+        }
+
+        let from_unvoiced = self.delta[unvoiced] + switch - (num_bins as f32).ln();
+        let mut delta = Vec::with_capacity(num_bins + 1);
+        let mut backpointer = Vec::with_capacity(num_bins + 1);
+        for (j, &(score, from)) in best.iter().enumerate() {
+            let (score, from) = if from_unvoiced > score { (from_unvoiced, unvoiced) } else { (score, from) };
+            delta.push(score + emit_voiced[j]);
+            backpointer.push(from);
+        }
+        let (best_voiced, best_voiced_idx) = self.delta[..num_bins]
+            .iter()
+            .enumerate()
+            .map(|(i, &d)| (d, i))
+            .fold((f32::NEG_INFINITY, unvoiced), |a, b| if b.0 > a.0 { b } else { a });
+        let (score, from) = if best_voiced + switch > self.delta[unvoiced] + stay {
+            (best_voiced + switch, best_voiced_idx)
+        } else {
+            (self.delta[unvoiced] + stay, unvoiced)
+        };
+        delta.push(score + emit_unvoiced);
+        backpointer.push(from);
+
+        // only differences matter, so keep the scores from drifting towards -inf
+        let max = delta.iter().fold(f32::NEG_INFINITY, |max, &d| max.max(d));
+        self.delta = delta.iter().map(|d| d - max).collect();
+        self.backpointers.push_back(backpointer);
+        self.spectra.push_back(gvec.to_vec());
+        if self.spectra.len() <= self.lag {
+            return None;
+        }
+
+        let mut state = (0..=num_bins).fold(unvoiced, |a, b| if self.delta[b] > self.delta[a] { b } else { a });
+        for backpointer in self.backpointers.iter().skip(1).rev() {
+            state = backpointer[state];
+        }
+        self.backpointers.pop_front();
+        let spectrum = self.spectra.pop_front()?;
+        Some((spectrum, (state != unvoiced).then_some(state)))
+    }
+}
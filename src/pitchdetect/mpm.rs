@@ -0,0 +1,102 @@
+use super::{ parabolic_peak, PitchEstimator };
+
+pub const MPM_WINDOW: usize = 2048;
+const MPM_K: f32 = 0.9; // key maxima within this fraction of the highest one are taken as the period
+const SILENCE_RMS: f32 = 1e-4;
+
+// McLeod pitch method: picks the first strong peak of the normalised square difference function
+pub struct MpmEstimator {
+    srate: f32,
+    window: usize,
+    min_tau: usize,
+    max_tau: usize,
+    nsdf: Vec<f32>,
+    pitch: (f32, f32), // (f0 in hz, clarity)
+}
+
+impl MpmEstimator {
+    pub fn new(min_freq: f32, max_freq: f32, srate: f32, window: usize) -> MpmEstimator {
+        let max_tau = ((srate / min_freq).ceil() as usize).min(window / 2);
+        let min_tau = ((srate / max_freq).floor() as usize).max(1);
+
+        MpmEstimator {
+            srate,
+            window,
+            min_tau,
+            max_tau,
+            nsdf: vec![0.0; max_tau + 1],
+            pitch: (0.0, 0.0),
+        }
+    }
+}
+
+impl PitchEstimator for MpmEstimator {
+    fn process(&mut self, buff: &[f32]) {
+        let frame = &buff[buff.len().saturating_sub(self.window)..];
+        self.pitch = (0.0, 0.0);
+
+        let rms = (frame.iter().map(|x| x * x).sum::<f32>() / (frame.len().max(1) as f32)).sqrt();
+        let max_tau = self.max_tau.min(frame.len() / 2);
+        if rms < SILENCE_RMS || max_tau <= self.min_tau {
+            return;
+        }
+
+        // n(tau) = 2 r(tau) / m(tau), bounded to [-1, 1]
+        for tau in 0..=max_tau {
+            let (head, tail) = (&frame[..frame.len() - tau], &frame[tau..]);
+            let acf: f32 = head
+                .iter()
+                .zip(tail)
+                .map(|(a, b)| a * b)
+                .sum();
+            let energy: f32 = head
+                .iter()
+                .zip(tail)
+                .map(|(a, b)| a * a + b * b)
+                .sum();
+            self.nsdf[tau] = if energy > 0.0 { (2.0 * acf) / energy } else { 0.0 };
+        }
+        let nsdf = &self.nsdf[..=max_tau];
+
+        // one key maximum per positive lobe, skipping the lobe around zero lag
+        let mut key_maxima: Vec<usize> = Vec::new();
+        let mut tau = nsdf
+            .iter()
+            .position(|&n| n <= 0.0)
+            .unwrap_or(nsdf.len());
+        let mut lobe_max: Option<usize> = None;
+        while tau < nsdf.len() {
+            if nsdf[tau] > 0.0 {
+                if lobe_max.is_none_or(|m| nsdf[tau] > nsdf[m]) {
+                    lobe_max = Some(tau);
+                }
+            } else if let Some(m) = lobe_max.take() {
+                key_maxima.push(m);
+            }
+            tau += 1;
+        }
+        // a lobe still open at max_tau only counts if its peak has already turned over
+        if let Some(m) = lobe_max.filter(|&m| m < max_tau) {
+            key_maxima.push(m);
+        }
+        key_maxima.retain(|&m| m >= self.min_tau);
+
+        let highest = key_maxima
+            .iter()
+            .map(|&m| nsdf[m])
+            .fold(0.0f32, f32::max);
+        if let Some(&period) = key_maxima.iter().find(|&&m| nsdf[m] >= MPM_K * highest) {
+            let (tau, clarity) = parabolic_peak(nsdf, period);
+            self.pitch = (self.srate / tau, clarity.clamp(0.0, 1.0));
+        }
+    }
+
+    // returns (f0 in hz, clarity)
+    fn get_pitch(&mut self) -> (f32, f32) {
+        self.pitch
+    }
+
+    fn window_len(&self) -> usize {
+        self.window
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::VecDeque;
+
+const ONSET_HISTORY_HOPS: usize = 8; // hops whose mean energy a new hop is compared against
+const ONSET_FLOOR_DB: f32 = -50.0; // hops quieter than this never count as an onset
+const ONSET_MIN_GAP: f64 = 50_000.0; // microseconds, so an attack spread over several hops fires once
+
+// energy-based onset detection: flags a hop whose level jumps well above the recent average,
+// which is what separates repeated notes of the same pitch
+pub struct OnsetDetector {
+    threshold_db: f32,
+    energies: VecDeque<f32>, // mean square of the previous hops
+    last_onset: Option<f64>,
+}
+
+impl OnsetDetector {
+    pub fn new(threshold_db: f32) -> OnsetDetector {
+        OnsetDetector {
+            threshold_db,
+            energies: VecDeque::with_capacity(ONSET_HISTORY_HOPS),
+            last_onset: None,
+        }
+    }
+
+    // rms is the level of the newest hop, which starts at timestamp
+    pub fn process(&mut self, rms: f32, timestamp: f64) -> bool {
+        let energy = (rms * rms).max(f32::EPSILON);
+        // before any history the hop is compared against the floor, so a stream can open with an onset
+        let reference = if self.energies.is_empty() {
+            (10.0f32).powf(ONSET_FLOOR_DB / 10.0)
+        } else {
+            self.energies.iter().sum::<f32>() / (self.energies.len() as f32)
+        };
+        if self.energies.len() == ONSET_HISTORY_HOPS {
+            self.energies.pop_front();
+        }
+        self.energies.push_back(energy);
+
+        let level_db = 10.0 * energy.log10();
+        let rise_db = 10.0 * (energy / reference.max(f32::EPSILON)).log10();
+        let settled = self.last_onset.is_none_or(|last| timestamp - last >= ONSET_MIN_GAP);
+        let onset = level_db > ONSET_FLOOR_DB && rise_db >= self.threshold_db && settled;
+        if onset {
+            self.last_onset = Some(timestamp);
+        }
+        onset
+    }
+}
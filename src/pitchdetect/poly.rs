@@ -0,0 +1,110 @@
+use super::{ goertzel::{ magnitude_confidence, GoertzelEstimator }, parabolic_peak, PitchEstimator };
+use crate::NOISE_THRESH;
+
+const MAX_POLYPHONY: usize = 6;
+const NUM_HARMONICS: usize = 8;
+const POLY_SALIENCE_RATIO: f32 = 0.2; // notes weaker than this fraction of the strongest one are dropped
+
+// multi-f0 estimation over the goertzel spectrum by iterative harmonic salience picking and subtraction
+pub struct PolyEstimator {
+    bank: GoertzelEstimator,
+    min_freq: f32,
+    bins_per_semitone: usize,
+    harmonic_offsets: Vec<usize>, // bin distance from a fundamental to each of its harmonics
+    residual: Vec<f32>,
+    pitches: Vec<(f32, f32)>, // (f0 in hz, salience relative to the strongest note)
+    magnitude: f32, // goertzel magnitude at the strongest note's fundamental
+}
+
+impl PolyEstimator {
+    pub fn new(min_freq: f32, srate: f32, bins_per_semitone: usize, window: usize) -> PolyEstimator {
+        let bins_per_semitone = bins_per_semitone.max(1);
+        let bins_per_octave = (12 * bins_per_semitone) as f32;
+        PolyEstimator {
+            bank: GoertzelEstimator::new(min_freq, srate, bins_per_semitone, window, None),
+            min_freq,
+            bins_per_semitone,
+            harmonic_offsets: (1..=NUM_HARMONICS)
+                .map(|h| (bins_per_octave * (h as f32).log2()).round() as usize)
+                .collect(),
+            residual: Vec::new(),
+            pitches: Vec::new(),
+            magnitude: 0.0,
+        }
+    }
+
+    fn salience(&self, idx: usize) -> f32 {
+        self.harmonic_offsets
+            .iter()
+            .enumerate()
+            .filter_map(|(h, offset)| self.residual.get(idx + offset).map(|mag| mag / ((h + 1) as f32)))
+            .sum()
+    }
+
+    fn bin_freq(&self, idx: f32) -> f32 {
+        self.min_freq * (2.0f32).powf(idx / ((12 * self.bins_per_semitone) as f32))
+    }
+}
+
+impl PitchEstimator for PolyEstimator {
+    fn process(&mut self, buff: &[f32]) {
+        self.bank.process(buff);
+        let gvec = self.bank.spectrum().unwrap_or(&[]).to_vec();
+        self.residual = gvec.clone();
+        self.pitches.clear();
+        self.magnitude = 0.0;
+
+        let mut strongest = 0.0;
+        while self.pitches.len() < MAX_POLYPHONY {
+            // a candidate fundamental has to be audible in its own bin, which rules out subharmonic ghosts
+            let best = (0..self.residual.len())
+                .filter(|&i| self.residual[i] >= NOISE_THRESH)
+                .map(|i| (i, self.salience(i)))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            let (idx, salience) = match best {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            if self.pitches.is_empty() {
+                strongest = salience;
+                self.magnitude = gvec[idx];
+            } else if salience < POLY_SALIENCE_RATIO * strongest {
+                break;
+            }
+
+            let offset = parabolic_peak(&gvec, idx).0.clamp(idx as f32 - 0.5, idx as f32 + 0.5);
+            self.pitches.push((self.bin_freq(offset), salience / strongest));
+
+            // remove the note's expected harmonic series, assuming partials fall off as 1/h
+            let fundamental = self.residual[idx];
+            let spread = self.bins_per_semitone / 2;
+            let last_bin = self.residual.len() - 1;
+            for (h, harmonic) in self.harmonic_offsets.iter().enumerate() {
+                let centre = idx + harmonic;
+                for bin in centre.saturating_sub(spread)..=(centre + spread).min(last_bin) {
+                    self.residual[bin] = (self.residual[bin] - fundamental / ((h + 1) as f32)).max(0.0);
+                }
+            }
+        }
+    }
+
+    // strongest note, as confident as the monophonic bank would be of its goertzel magnitude
+    fn get_pitch(&mut self) -> (f32, f32) {
+        match self.pitches.first() {
+            Some(&(f0, _)) => (f0, magnitude_confidence(self.magnitude)),
+            None => (0.0, 0.0),
+        }
+    }
+
+    fn get_pitches(&mut self) -> Vec<(f32, f32)> {
+        self.pitches.clone()
+    }
+
+    fn window_len(&self) -> usize {
+        self.bank.window_len()
+    }
+
+    fn spectrum(&self) -> Option<&[f32]> {
+        self.bank.spectrum()
+    }
+}
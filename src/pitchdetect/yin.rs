@@ -0,0 +1,125 @@
+use super::{ parabolic_peak, PitchEstimator };
+
+const YIN_THRESH: f32 = 0.15;
+pub const YIN_WINDOW: usize = 4096;
+const SILENCE_RMS: f32 = 1e-4;
+
+// pYIN spreads its threshold over a beta(2, 18) prior instead of fixing it
+const PYIN_NUM_THRESH: usize = 100;
+const PYIN_BETA_A: f32 = 2.0;
+const PYIN_BETA_B: f32 = 18.0;
+const PYIN_ABS_MIN_PROB: f32 = 0.01; // weight given to the global minimum when no trough passes a threshold
+
+pub struct YinEstimator {
+    srate: f32,
+    window: usize,
+    min_tau: usize,
+    max_tau: usize,
+    probabilistic: bool,
+    thresh_prior: Vec<f32>,
+    cmndf: Vec<f32>,
+    candidates: Vec<(f32, f32)>, // (frequency in hz, probability) for the current frame
+}
+
+impl YinEstimator {
+    pub fn new(min_freq: f32, max_freq: f32, srate: f32, window: usize, probabilistic: bool) -> YinEstimator {
+        let max_tau = ((srate / min_freq).ceil() as usize).min(window / 2);
+        let min_tau = ((srate / max_freq).floor() as usize).max(2);
+
+        // discretised beta pdf over thresholds 0.01..=1.0, normalised to sum to one
+        let pdf = (1..=PYIN_NUM_THRESH)
+            .map(|i| {
+                let x = (i as f32) / (PYIN_NUM_THRESH as f32);
+                x.powf(PYIN_BETA_A - 1.0) * (1.0 - x).powf(PYIN_BETA_B - 1.0)
+            })
+            .collect::<Vec<f32>>();
+        let total: f32 = pdf.iter().sum();
+
+        YinEstimator {
+            srate,
+            window,
+            min_tau,
+            max_tau,
+            probabilistic,
+            thresh_prior: pdf.iter().map(|p| p / total).collect(),
+            cmndf: vec![1.0; max_tau + 1],
+            candidates: Vec::new(),
+        }
+    }
+}
+
+impl PitchEstimator for YinEstimator {
+    // computes the cumulative mean normalised difference function over the last window samples
+    fn process(&mut self, buff: &[f32]) {
+        let frame = &buff[buff.len().saturating_sub(self.window)..];
+        self.candidates.clear();
+
+        let rms = (frame.iter().map(|x| x * x).sum::<f32>() / (frame.len().max(1) as f32)).sqrt();
+        let max_tau = self.max_tau.min(frame.len() / 2);
+        if rms < SILENCE_RMS || max_tau <= self.min_tau {
+            return;
+        }
+        let integration_len = frame.len() - max_tau;
+
+        self.cmndf[0] = 1.0;
+        let mut running_sum = 0.0;
+        for tau in 1..=max_tau {
+            let diff: f32 = frame[..integration_len]
+                .iter()
+                .zip(&frame[tau..tau + integration_len])
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum();
+            running_sum += diff;
+            self.cmndf[tau] = if running_sum > 0.0 { (diff * (tau as f32)) / running_sum } else { 1.0 };
+        }
+
+        // local minima of the cmndf, in order of increasing lag
+        let d = &self.cmndf[..=max_tau];
+        let troughs = (self.min_tau..max_tau)
+            .filter(|&tau| d[tau] < d[tau - 1] && d[tau] <= d[tau + 1])
+            .collect::<Vec<usize>>();
+        let global_min = match troughs.iter().min_by(|&&a, &&b| d[a].total_cmp(&d[b])) {
+            Some(&tau) => tau,
+            None => return,
+        };
+        let first_below = |thresh: f32| troughs.iter().find(|&&tau| d[tau] < thresh).copied();
+
+        let mut tau_probs: Vec<(usize, f32)> = Vec::new();
+        if self.probabilistic {
+            for (i, prior) in self.thresh_prior.iter().enumerate() {
+                let thresh = ((i + 1) as f32) / (PYIN_NUM_THRESH as f32);
+                let (tau, p) = match first_below(thresh) {
+                    Some(tau) => (tau, *prior),
+                    None => (global_min, prior * PYIN_ABS_MIN_PROB),
+                };
+                match tau_probs.iter_mut().find(|el| el.0 == tau) {
+                    Some(el) => {
+                        el.1 += p;
+                    }
+                    None => tau_probs.push((tau, p)),
+                }
+            }
+        } else if let Some(tau) = first_below(YIN_THRESH) {
+            // aperiodicity of the chosen period is the cmndf value at its trough
+            tau_probs.push((tau, 1.0 - d[tau]));
+        }
+
+        for (tau, p) in tau_probs {
+            let period = parabolic_peak(d, tau).0;
+            self.candidates.push((self.srate / period, p));
+        }
+    }
+
+    // returns (f0 in hz, voiced probability)
+    fn get_pitch(&mut self) -> (f32, f32) {
+        let best = self.candidates.iter().max_by(|a, b| a.1.total_cmp(&b.1));
+        match best {
+            Some(&(f0, _)) => (f0, self.candidates.iter().map(|c| c.1).sum::<f32>().min(1.0)),
+            None => (0.0, 0.0),
+        }
+    }
+
+    fn window_len(&self) -> usize {
+        self.window
+    }
+}
@@ -0,0 +1,177 @@
+use crate::tuning::Tuning;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Scale {
+    // every key, notes pass through as detected
+    Chromatic,
+    Major,
+    // natural minor
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+}
+
+impl Scale {
+    // semitones above the key
+    fn intervals(&self) -> &'static [i32] {
+        match self {
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Snap {
+    // the scale note closest to the sung pitch
+    Nearest,
+    // the next scale note above
+    Up,
+    // the next scale note below
+    Down,
+}
+
+// keeps output notes in key by moving keys outside a set of pitch classes onto one inside it
+#[derive(Clone, Debug)]
+pub struct Quantizer {
+    allowed: [bool; 12], // by pitch class, C first
+    snap: Snap,
+}
+
+impl Quantizer {
+    // a non-empty pitch_classes is a custom set that replaces scale and key
+    pub fn new(scale: Scale, key: i32, pitch_classes: &[i32], snap: Snap) -> Quantizer {
+        let mut allowed = [false; 12];
+        if pitch_classes.is_empty() {
+            for interval in scale.intervals() {
+                allowed[(key + interval).rem_euclid(12) as usize] = true;
+            }
+        } else {
+            for pitch_class in pitch_classes {
+                allowed[pitch_class.rem_euclid(12) as usize] = true;
+            }
+        }
+        Quantizer { allowed, snap }
+    }
+
+    fn allows(&self, key: u8) -> bool {
+        self.allowed[(key % 12) as usize]
+    }
+
+    // pitch is the continuous pitch the key was detected from, which breaks ties for nearest
+    pub fn snap(&self, key: u8, pitch: f32, tuning: &Tuning) -> u8 {
+        if self.allows(key) {
+            return key;
+        }
+        let above = (key..=127).find(|&key| self.allows(key));
+        let below = (0..=key).rev().find(|&key| self.allows(key));
+        let snapped = match self.snap {
+            Snap::Up => above.or(below),
+            Snap::Down => below.or(above),
+            // measured from the key rather than the pitch, which only settles ties
+            Snap::Nearest => {
+                let distance = |to: u8, from: f32| (tuning.key_pitch(to) - from).abs();
+                above
+                    .into_iter()
+                    .chain(below)
+                    .min_by(|&a, &b| {
+                        distance(a, tuning.key_pitch(key))
+                            .total_cmp(&distance(b, tuning.key_pitch(key)))
+                            .then(distance(a, pitch).total_cmp(&distance(b, pitch)))
+                    })
+            }
+        };
+        snapped.unwrap_or(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuning::TuningSystem;
+
+    fn equal() -> Tuning {
+        Tuning::new(440.0, TuningSystem::Equal, 0, None, None).unwrap()
+    }
+
+    #[test]
+    fn chromatic_passes_every_key_through() {
+        let tuning = equal();
+        let quantizer = Quantizer::new(Scale::Chromatic, 0, &[], Snap::Nearest);
+        assert!((0..=127).all(|key| quantizer.snap(key, key as f32 + 0.4, &tuning) == key));
+    }
+
+    #[test]
+    fn empty_pitch_classes_fall_back_to_the_scale() {
+        let tuning = equal();
+        // d major has C# and F# but no C or F
+        let quantizer = Quantizer::new(Scale::Major, 2, &[], Snap::Up);
+        assert_eq!(quantizer.snap(61, 61.0, &tuning), 61);
+        assert_eq!(quantizer.snap(66, 66.0, &tuning), 66);
+        assert_eq!(quantizer.snap(60, 60.0, &tuning), 61);
+        assert_eq!(quantizer.snap(65, 65.0, &tuning), 66);
+    }
+
+    #[test]
+    fn custom_pitch_classes_replace_scale_and_key() {
+        let tuning = equal();
+        let quantizer = Quantizer::new(Scale::Major, 0, &[0, 3, 7], Snap::Down);
+        assert_eq!(quantizer.snap(63, 63.0, &tuning), 63);
+        assert_eq!(quantizer.snap(64, 64.0, &tuning), 63);
+        assert_eq!(quantizer.snap(62, 62.0, &tuning), 60);
+    }
+
+    #[test]
+    fn nearest_ties_are_broken_by_the_sung_pitch() {
+        let tuning = equal();
+        let quantizer = Quantizer::new(Scale::Major, 0, &[], Snap::Nearest);
+        // C# is a semitone from both C and D
+        assert_eq!(quantizer.snap(61, 60.8, &tuning), 60);
+        assert_eq!(quantizer.snap(61, 61.2, &tuning), 62);
+        // and exactly between them it goes up
+        assert_eq!(quantizer.snap(61, 61.0, &tuning), 62);
+    }
+
+    #[test]
+    fn nearest_is_measured_from_the_key_in_the_tuning() {
+        // in just intonation above C, C# sits 112 cents above C but only 92 below D
+        let tuning = Tuning::new(440.0, TuningSystem::Just, 0, None, None).unwrap();
+        let quantizer = Quantizer::new(Scale::Major, 0, &[], Snap::Nearest);
+        assert_eq!(quantizer.snap(61, 60.6, &tuning), 62);
+    }
+
+    #[test]
+    fn snapping_wraps_across_the_octave() {
+        let tuning = equal();
+        let up = Quantizer::new(Scale::Chromatic, 0, &[0], Snap::Up);
+        assert_eq!(up.snap(71, 71.0, &tuning), 72);
+        let down = Quantizer::new(Scale::Chromatic, 0, &[2], Snap::Down);
+        assert_eq!(down.snap(59, 59.0, &tuning), 50);
+        let nearest = Quantizer::new(Scale::Chromatic, 0, &[0, 4], Snap::Nearest);
+        assert_eq!(nearest.snap(70, 70.0, &tuning), 72);
+        assert_eq!(nearest.snap(66, 66.0, &tuning), 64);
+    }
+
+    #[test]
+    fn snapping_turns_back_at_the_ends_of_the_keyboard() {
+        let tuning = equal();
+        // G9 is the top key, so there is no C above it
+        let up = Quantizer::new(Scale::Chromatic, 0, &[0], Snap::Up);
+        assert_eq!(up.snap(127, 127.0, &tuning), 120);
+        let down = Quantizer::new(Scale::Chromatic, 0, &[2], Snap::Down);
+        assert_eq!(down.snap(0, 0.0, &tuning), 2);
+    }
+}
@@ -0,0 +1,553 @@
+use std::{ error::Error, fs, path::Path };
+
+const NUM_KEYS: usize = 128;
+const DEFAULT_MIDDLE_KEY: i32 = 60; // where scale degree 0 lands without a keyboard mapping
+const REFERENCE_KEY: i32 = 69; // key tuned to --a4 without a keyboard mapping
+const REFERENCE_TIME_CONSTANT: f64 = 4e6; // microseconds, how far back the reference estimate mostly looks
+const REFERENCE_MIN_FRAMES: f64 = 32.0; // voiced frames needed before the estimate is trusted
+
+// 5-limit just intonation, as ratios above the tonic
+const JUST_RATIOS: [(f64, f64); 12] = [
+    (1.0, 1.0),
+    (16.0, 15.0),
+    (9.0, 8.0),
+    (6.0, 5.0),
+    (5.0, 4.0),
+    (4.0, 3.0),
+    (45.0, 32.0),
+    (3.0, 2.0),
+    (8.0, 5.0),
+    (5.0, 3.0),
+    (9.0, 5.0),
+    (15.0, 8.0),
+];
+
+const PITCH_CLASSES: [(&str, i32); 17] = [
+    ("C", 0),
+    ("C#", 1),
+    ("Db", 1),
+    ("D", 2),
+    ("D#", 3),
+    ("Eb", 3),
+    ("E", 4),
+    ("F", 5),
+    ("F#", 6),
+    ("Gb", 6),
+    ("G", 7),
+    ("G#", 8),
+    ("Ab", 8),
+    ("A", 9),
+    ("A#", 10),
+    ("Bb", 10),
+    ("B", 11),
+];
+
+// clap parser for note names, giving their pitch class with C as 0
+pub fn parse_pitch_class(name: &str) -> Result<i32, String> {
+    PITCH_CLASSES
+        .iter()
+        .find(|(pitch_class, _)| pitch_class.eq_ignore_ascii_case(name.trim()))
+        .map(|&(_, pitch_class)| pitch_class)
+        .ok_or_else(|| format!("unknown note '{}', expected a note name such as C, F# or Bb", name))
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum TuningSystem {
+    // twelve equal semitones
+    Equal,
+    // 5-limit just intonation above --tonic
+    Just,
+}
+
+// a scale as cents above its first degree, the last entry being the interval it repeats at
+struct Scale {
+    cents: Vec<f64>,
+}
+
+impl Scale {
+    fn equal() -> Scale {
+        Scale { cents: (1..=12).map(|step| (step as f64) * 100.0).collect() }
+    }
+
+    fn just() -> Scale {
+        let mut cents = JUST_RATIOS[1..]
+            .iter()
+            .map(|&(num, den)| 1200.0 * (num / den).log2())
+            .collect::<Vec<f64>>();
+        cents.push(1200.0);
+        Scale { cents }
+    }
+
+    fn read_scl(path: &Path) -> Result<Scale, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|err| format!("couldn't read '{}': {}", path.display(), err))?;
+        Ok(Scale::parse_scl(&text).map_err(|what| format!("'{}' isn't a valid scala file: {}", path.display(), what))?)
+    }
+
+    // scala .scl: a description line, the number of notes, then one pitch per line as cents
+    // (anything with a '.') or a ratio, lines starting with '!' being comments
+    fn parse_scl(text: &str) -> Result<Scale, String> {
+        let mut lines = text.lines().filter(|line| !line.trim_start().starts_with('!'));
+        // the description may be blank, any other blank line is skipped
+        lines.next().ok_or_else(|| "missing description".to_string())?;
+        let mut lines = lines.filter(|line| !line.trim().is_empty());
+        let count = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|count| count.parse::<usize>().ok())
+            .ok_or_else(|| "missing note count".to_string())?;
+        let cents = lines
+            .take(count)
+            .map(|line| {
+                let pitch = line.split_whitespace().next().unwrap_or_default();
+                parse_pitch(pitch).ok_or_else(|| format!("can't read pitch '{}'", pitch))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+        if count == 0 || cents.len() < count {
+            return Err(format!("expected {} notes, found {}", count, cents.len()));
+        }
+        Ok(Scale { cents })
+    }
+
+    // cents of any degree, counting on through the repeats
+    fn degree_cents(&self, degree: i32) -> f64 {
+        let size = self.cents.len() as i32;
+        let (repeats, step) = (degree.div_euclid(size), degree.rem_euclid(size));
+        let period = self.cents[self.cents.len() - 1];
+        let cents = if step == 0 { 0.0 } else { self.cents[(step - 1) as usize] };
+        (repeats as f64) * period + cents
+    }
+}
+
+fn parse_pitch(pitch: &str) -> Option<f64> {
+    if pitch.contains('.') {
+        return pitch.parse::<f64>().ok();
+    }
+    let (num, den) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let ratio = num.parse::<f64>().ok()? / den.parse::<f64>().ok()?;
+    (ratio > 0.0).then(|| 1200.0 * ratio.log2())
+}
+
+// scala .kbm keyboard mapping: which scale degree each midi key plays and the frequency it is anchored to
+struct KeyboardMap {
+    size: usize, // 0 maps consecutive keys to consecutive degrees
+    first_key: i32,
+    last_key: i32,
+    middle_key: i32, // key playing degree 0
+    reference_key: i32,
+    reference_freq: f64,
+    octave_degree: i32, // degree the mapping repeats at, 0 for the scale's own period
+    degrees: Vec<Option<i32>>, // per key of the pattern, none for keys left silent
+}
+
+impl KeyboardMap {
+    fn linear(middle_key: i32, reference_key: i32, reference_freq: f64) -> KeyboardMap {
+        KeyboardMap {
+            size: 0,
+            first_key: 0,
+            last_key: (NUM_KEYS as i32) - 1,
+            middle_key,
+            reference_key,
+            reference_freq,
+            octave_degree: 0,
+            degrees: Vec::new(),
+        }
+    }
+
+    fn read_kbm(path: &Path) -> Result<KeyboardMap, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|err| format!("couldn't read '{}': {}", path.display(), err))?;
+        Ok(KeyboardMap::parse_kbm(&text).map_err(|what| format!("'{}' isn't a valid keyboard mapping: {}", path.display(), what))?)
+    }
+
+    // values come one per line in the order of the fields, lines starting with '!' being comments,
+    // and the pattern's unmapped keys are written as 'x'
+    fn parse_kbm(text: &str) -> Result<KeyboardMap, String> {
+        let mut values = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('!'))
+            .filter_map(|line| line.split_whitespace().next());
+        let mut next_int = |field: &str| {
+            values
+                .next()
+                .and_then(|value| value.parse::<i32>().ok())
+                .ok_or_else(|| format!("missing or unreadable {}", field))
+        };
+        let size = next_int("map size")?.max(0) as usize;
+        let first_key = next_int("first note")?;
+        let last_key = next_int("last note")?;
+        let middle_key = next_int("middle note")?;
+        let reference_key = next_int("reference note")?;
+        let reference_freq = values
+            .next()
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|&freq| freq > 0.0)
+            .ok_or_else(|| "missing or unreadable reference frequency".to_string())?;
+        let octave_degree = values
+            .next()
+            .and_then(|value| value.parse::<i32>().ok())
+            .ok_or_else(|| "missing or unreadable formal octave degree".to_string())?;
+        let degrees = values
+            .take(size)
+            .map(|value| match value {
+                "x" | "X" => Ok(None),
+                degree => degree.parse::<i32>().map(Some).map_err(|_| format!("can't read mapping entry '{}'", degree)),
+            })
+            .collect::<Result<Vec<Option<i32>>, String>>()?;
+        if degrees.len() < size {
+            return Err(format!("expected {} mapping entries, found {}", size, degrees.len()));
+        }
+        Ok(KeyboardMap { size, first_key, last_key, middle_key, reference_key, reference_freq, octave_degree, degrees })
+    }
+
+    // cents above the middle key, none for keys the mapping leaves silent
+    fn key_cents(&self, key: i32, scale: &Scale) -> Option<f64> {
+        if key < self.first_key || key > self.last_key {
+            return None;
+        }
+        let offset = key - self.middle_key;
+        if self.size == 0 {
+            return Some(scale.degree_cents(offset));
+        }
+        let size = self.size as i32;
+        let degree = self.degrees[offset.rem_euclid(size) as usize]?;
+        let octave = if self.octave_degree == 0 { scale.degree_cents(scale.cents.len() as i32) } else { scale.degree_cents(self.octave_degree) };
+        Some((offset.div_euclid(size) as f64) * octave + scale.degree_cents(degree))
+    }
+}
+
+// maps between frequencies and midi keys under the ensemble's tuning. pitches are continuous
+// midi note numbers on the equal tempered scale at a4, so intonation and pitch bend stay in
+// semitones, while each key is quantised to and bent from the pitch it has in the tuning
+#[derive(Clone, Debug)]
+pub struct Tuning {
+    a4: f32,
+    reference: f32, // a4 the input is heard against, moved off a4 by an estimated reference
+    keys: Vec<Option<f32>>, // pitch of every midi key, none if the keyboard mapping leaves it silent
+}
+
+impl Tuning {
+    pub fn new(
+        a4: f32,
+        system: TuningSystem,
+        tonic: i32, // pitch class, C being 0
+        scl: Option<&Path>,
+        kbm: Option<&Path>
+    ) -> Result<Tuning, Box<dyn Error>> {
+        let scale = scl.map(Scale::read_scl).transpose()?;
+        let map = kbm.map(KeyboardMap::read_kbm).transpose()?;
+        Tuning::from_parts(a4, system, tonic, scale, map)
+    }
+
+    // a scale or keyboard mapping given here takes the place of the tuning system's own
+    fn from_parts(
+        a4: f32,
+        system: TuningSystem,
+        tonic: i32,
+        scale: Option<Scale>,
+        map: Option<KeyboardMap>
+    ) -> Result<Tuning, Box<dyn Error>> {
+        if a4 <= 0.0 {
+            return Err("--a4 has to be a positive frequency".into());
+        }
+        let scale = match (scale, system) {
+            (Some(scale), _) => scale,
+            (None, TuningSystem::Equal) => Scale::equal(),
+            (None, TuningSystem::Just) => Scale::just(),
+        };
+        let map = match (map, system) {
+            (Some(map), _) => map,
+            // pure intervals above a tonic that stays where equal temperament puts it
+            (None, TuningSystem::Just) => {
+                let tonic_key = DEFAULT_MIDDLE_KEY + tonic;
+                let tonic_freq = (a4 as f64) * (2.0f64).powf(((tonic_key - REFERENCE_KEY) as f64) / 12.0);
+                KeyboardMap::linear(tonic_key, tonic_key, tonic_freq)
+            }
+            (None, TuningSystem::Equal) => KeyboardMap::linear(DEFAULT_MIDDLE_KEY, REFERENCE_KEY, a4 as f64),
+        };
+
+        let reference_cents = map
+            .key_cents(map.reference_key, &scale)
+            .ok_or("the keyboard mapping's reference note isn't mapped to a scale degree")?;
+        let reference_pitch = 69.0 + 12.0 * (map.reference_freq / (a4 as f64)).log2();
+        let keys = (0..NUM_KEYS as i32)
+            .map(|key| map.key_cents(key, &scale).map(|cents| (reference_pitch + (cents - reference_cents) / 100.0) as f32))
+            .collect::<Vec<Option<f32>>>();
+        if keys.iter().all(|pitch| pitch.is_none()) {
+            return Err("the keyboard mapping doesn't map any MIDI keys".into());
+        }
+        Ok(Tuning { a4, reference: a4, keys })
+    }
+
+    // continuous midi pitch, e.g. 69.5 is a quarter tone above A4
+    pub fn pitch(&self, frequency: f32) -> f32 {
+        12.0 * f32::log2(frequency / self.reference) + 69.0
+    }
+
+    pub fn reference(&self) -> f32 {
+        self.reference
+    }
+
+    // hears the input against another a4, e.g. an ensemble tuned to 443 hz, keeping the tuning's shape
+    pub fn set_reference(&mut self, a4: f32) {
+        self.reference = a4;
+    }
+
+    // where the key sounds in this tuning
+    pub fn key_pitch(&self, key: u8) -> f32 {
+        self.keys
+            .get(key as usize)
+            .copied()
+            .flatten()
+            .unwrap_or(key as f32)
+    }
+
+    // the mapped key sounding closest to a continuous pitch
+    pub fn nearest_key(&self, pitch: f32) -> u8 {
+        self.keys
+            .iter()
+            .enumerate()
+            .filter_map(|(key, key_pitch)| key_pitch.map(|key_pitch| (key, (pitch - key_pitch).abs())))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(key, _)| key as u8)
+            .unwrap_or_default()
+    }
+
+    pub fn note(&self, frequency: f32) -> u8 {
+        self.nearest_key(self.pitch(frequency))
+    }
+
+    // semitones from a key up to the next mapped key, or down to it
+    fn key_spacing(&self, key: u8, above: bool) -> Option<f32> {
+        let pitch = self.keys.get(key as usize).copied().flatten()?;
+        self.keys
+            .iter()
+            .flatten()
+            .map(|&other| if above { other - pitch } else { pitch - other })
+            .filter(|&spacing| spacing > 0.0)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+// estimates the reference a performer is tuned to from how far their notes sit from the tuning's keys,
+// as a circular mean over the last few seconds so deviations either side of a boundary don't cancel.
+// each deviation wraps at the step to the key it leans towards rather than at a semitone, so the
+// uneven steps of just or scala tunings don't read as the performer being out of tune
+#[derive(Default)]
+pub struct ReferenceEstimator {
+    sin: f64,
+    cos: f64,
+    inverse_steps: f64, // decayed sum of the reciprocal steps, in semitones, the deviations were wrapped at
+    weight: f64, // decayed count of the frames behind the sums
+    last_time: Option<f64>,
+}
+
+impl ReferenceEstimator {
+    // adds a voiced frame, returning the estimated a4 once enough has been heard
+    pub fn observe(&mut self, tuning: &Tuning, frequency: f32, timestamp: f64) -> Option<f32> {
+        // measured against the configured a4, so the estimate doesn't chase itself
+        let pitch = 12.0 * f32::log2(frequency / tuning.a4) + 69.0;
+        let key = tuning.nearest_key(pitch);
+        let deviation = pitch - tuning.key_pitch(key);
+        let step = tuning.key_spacing(key, deviation >= 0.0).unwrap_or(1.0) as f64;
+
+        let elapsed = self.last_time.map_or(0.0, |last| (timestamp - last).max(0.0));
+        let decay = (-elapsed / REFERENCE_TIME_CONSTANT).exp();
+        let angle = (std::f64::consts::TAU * (deviation as f64)) / step;
+        self.sin = self.sin * decay + angle.sin();
+        self.cos = self.cos * decay + angle.cos();
+        self.inverse_steps = self.inverse_steps * decay + 1.0 / step;
+        self.weight = self.weight * decay + 1.0;
+        self.last_time = Some(timestamp);
+
+        if self.weight < REFERENCE_MIN_FRAMES {
+            return None;
+        }
+        let offset = (self.sin.atan2(self.cos) / std::f64::consts::TAU) * (self.weight / self.inverse_steps);
+        Some(tuning.a4 * (2.0f32).powf((offset as f32) / 12.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE_KEYS_KBM: &str = "! white keys only, the black keys left silent
+12
+0
+127
+60
+69
+440.0
+12
+! mapping
+0
+x
+2
+x
+4
+5
+x
+7
+x
+9
+x
+11
+";
+
+    fn freq(tuning: &Tuning, pitch: f32) -> f32 {
+        tuning.reference() * (2.0f32).powf((pitch - 69.0) / 12.0)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-3, "{} isn't {}", actual, expected);
+    }
+
+    #[test]
+    fn scl_skips_comments_and_blank_lines() {
+        let scale = Scale::parse_scl("! meantone.scl\n!\n\n 3\n! the notes\n\n200.0\n\n500.0 fourth\n1200.0\n").unwrap();
+        assert_eq!(scale.cents, vec![200.0, 500.0, 1200.0]);
+    }
+
+    #[test]
+    fn scl_reads_cents_and_ratios() {
+        let scale = Scale::parse_scl("mixed\n5\n100.\n-5.5\n3/2\n5/3\n2\n").unwrap();
+        assert_close(scale.cents[0], 100.0);
+        assert_close(scale.cents[1], -5.5);
+        assert_close(scale.cents[2], 701.955);
+        assert_close(scale.cents[3], 884.359);
+        assert_close(scale.cents[4], 1200.0);
+    }
+
+    #[test]
+    fn scl_repeats_at_its_last_degree() {
+        let scale = Scale::parse_scl("fifths\n2\n3/2\n2/1\n").unwrap();
+        assert_close(scale.degree_cents(0), 0.0);
+        assert_close(scale.degree_cents(2), 1200.0);
+        assert_close(scale.degree_cents(3), 1901.955);
+        assert_close(scale.degree_cents(-1), -498.045);
+    }
+
+    #[test]
+    fn scl_rejects_bad_files() {
+        assert!(Scale::parse_scl("").is_err());
+        assert!(Scale::parse_scl("no count\n").is_err());
+        assert!(Scale::parse_scl("empty\n0\n").is_err());
+        assert!(Scale::parse_scl("short\n3\n3/2\n2/1\n").is_err());
+        assert!(Scale::parse_scl("unreadable\n1\nfifth\n").is_err());
+        assert!(Scale::parse_scl("negative ratio\n1\n-2/1\n").is_err());
+    }
+
+    #[test]
+    fn kbm_leaves_unmapped_keys_silent() {
+        let map = KeyboardMap::parse_kbm(WHITE_KEYS_KBM).unwrap();
+        let scale = Scale::equal();
+        assert_eq!(map.degrees.len(), 12);
+        assert_eq!(map.key_cents(61, &scale), None);
+        assert_close(map.key_cents(60, &scale).unwrap(), 0.0);
+        assert_close(map.key_cents(64, &scale).unwrap(), 400.0);
+        assert_close(map.key_cents(72, &scale).unwrap(), 1200.0);
+        assert_close(map.key_cents(59, &scale).unwrap(), -100.0);
+        assert_eq!(map.key_cents(58, &scale), None);
+
+        let tuning = Tuning::from_parts(440.0, TuningSystem::Equal, 0, None, Some(map)).unwrap();
+        assert_eq!(tuning.nearest_key(61.2), 62);
+        assert_eq!(tuning.nearest_key(60.8), 60);
+        assert_eq!(tuning.note(freq(&tuning, 70.0)), 69);
+    }
+
+    #[test]
+    fn kbm_keeps_to_its_key_range() {
+        let map = KeyboardMap::parse_kbm("0\n48\n72\n60\n69\n440.0\n0\n").unwrap();
+        let scale = Scale::equal();
+        assert_eq!(map.key_cents(47, &scale), None);
+        assert_close(map.key_cents(48, &scale).unwrap(), -1200.0);
+        assert_eq!(map.key_cents(73, &scale), None);
+    }
+
+    #[test]
+    fn kbm_rejects_bad_files() {
+        assert!(KeyboardMap::parse_kbm("12\n0\n127\n60\n69\n").is_err());
+        assert!(KeyboardMap::parse_kbm("2\n0\n127\n60\n69\n440.0\n12\n0\n").is_err());
+        assert!(KeyboardMap::parse_kbm("1\n0\n127\n60\n69\n440.0\n12\ny\n").is_err());
+        let silent = KeyboardMap::parse_kbm("1\n0\n127\n60\n60\n440.0\n12\nx\n").unwrap();
+        assert!(Tuning::from_parts(440.0, TuningSystem::Equal, 0, None, Some(silent)).is_err());
+    }
+
+    #[test]
+    fn scala_reference_note_sounds_at_the_reference_frequency() {
+        let scale = Scale::parse_scl("pythagorean pentatonic\n5\n9/8\n81/64\n3/2\n27/16\n2/1\n").unwrap();
+        let map = KeyboardMap::parse_kbm("0\n0\n127\n60\n60\n261.6256\n0\n").unwrap();
+        let tuning = Tuning::from_parts(440.0, TuningSystem::Equal, 0, Some(scale), Some(map)).unwrap();
+        assert_close(tuning.key_pitch(60) as f64, 60.0);
+        assert_close(tuning.key_pitch(61) as f64, 62.039);
+        assert_close(tuning.key_pitch(65) as f64, 72.0);
+    }
+
+    #[test]
+    fn just_intonation_is_pure_above_the_tonic() {
+        let tuning = Tuning::new(440.0, TuningSystem::Just, parse_pitch_class("D").unwrap(), None, None).unwrap();
+        assert_close(tuning.key_pitch(62) as f64, 62.0);
+        assert_close(tuning.key_pitch(66) as f64, 65.863);
+        assert_close(tuning.key_pitch(69) as f64, 69.020);
+        assert_close(tuning.key_pitch(74) as f64, 74.0);
+        assert_close(tuning.key_pitch(61) as f64, 60.882);
+    }
+
+    #[test]
+    fn notes_round_trip_through_their_frequencies() {
+        let scale = Scale::parse_scl("7-edo\n7\n171.429\n342.857\n514.286\n685.714\n857.143\n1028.571\n2/1\n").unwrap();
+        let tunings = [
+            Tuning::new(440.0, TuningSystem::Equal, 0, None, None).unwrap(),
+            Tuning::new(432.0, TuningSystem::Equal, 0, None, None).unwrap(),
+            Tuning::new(440.0, TuningSystem::Just, parse_pitch_class("Eb").unwrap(), None, None).unwrap(),
+            Tuning::from_parts(440.0, TuningSystem::Equal, 0, Some(scale), None).unwrap(),
+            Tuning::from_parts(440.0, TuningSystem::Equal, 0, None, KeyboardMap::parse_kbm(WHITE_KEYS_KBM).ok()).unwrap(),
+        ];
+        for tuning in tunings.iter() {
+            for key in (0..NUM_KEYS as u8).filter(|&key| tuning.keys[key as usize].is_some()) {
+                assert_eq!(tuning.note(freq(tuning, tuning.key_pitch(key))), key);
+            }
+        }
+        assert_eq!(tunings[0].note(440.0), 69);
+        assert_eq!(tunings[1].note(432.0), 69);
+    }
+
+    // plays every key from 55 to 79 in turn at the performer's offset, returning the estimated
+    // offset in semitones
+    fn estimate(tuning: &Tuning, offset: f32) -> f64 {
+        let mut estimator = ReferenceEstimator::default();
+        let mut estimate = None;
+        for (frame, key) in (55..80u8).cycle().take(200).enumerate() {
+            let frequency = tuning.a4 * (2.0f32).powf((tuning.key_pitch(key) + offset - 69.0) / 12.0);
+            estimate = estimator.observe(tuning, frequency, (frame as f64) * 1e4).or(estimate);
+        }
+        12.0 * (estimate.unwrap() / tuning.a4).log2() as f64
+    }
+
+    #[test]
+    fn reference_estimate_follows_the_performer() {
+        let tuning = Tuning::new(440.0, TuningSystem::Equal, 0, None, None).unwrap();
+        assert_close(estimate(&tuning, 0.0), 0.0);
+        assert_close(estimate(&tuning, -0.3), -0.3);
+    }
+
+    #[test]
+    fn reference_estimate_is_unbiased_by_uneven_steps() {
+        let just = Tuning::new(440.0, TuningSystem::Just, 0, None, None).unwrap();
+        assert_close(estimate(&just, 0.0), 0.0);
+        assert_close(estimate(&just, 0.2), 0.2);
+        // steps of 171 cents, where an offset of 60 cents would alias to -40 against semitones
+        let scale = Scale::parse_scl("7-edo\n7\n171.429\n342.857\n514.286\n685.714\n857.143\n1028.571\n2/1\n").unwrap();
+        let seven = Tuning::from_parts(440.0, TuningSystem::Equal, 0, Some(scale), None).unwrap();
+        assert_close(estimate(&seven, 0.0), 0.0);
+        assert_close(estimate(&seven, 0.6), 0.6);
+    }
+
+    #[test]
+    fn set_reference_moves_what_the_input_is_heard_against() {
+        let mut tuning = Tuning::new(440.0, TuningSystem::Equal, 0, None, None).unwrap();
+        assert_close(tuning.pitch(452.0) as f64, 69.466);
+        tuning.set_reference(452.0);
+        assert_close(tuning.pitch(452.0) as f64, 69.0);
+        assert_eq!(tuning.note(452.0 * 1.5), 76);
+    }
+}